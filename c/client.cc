@@ -3,8 +3,7 @@
 #include "pir/prng/aes_128_ctr_seeded_prng.h"
 #include "pir/dense_dpf_pir_client.h"
 #include "dpf/distributed_point_function.h"
-#include "nlohmann/json.hpp"
-#include "base64_utils.h"
+#include "records.h"
 
 #include <memory>
 #include <string>
@@ -12,6 +11,7 @@
 #include <mutex>
 #include <cstring>
 #include <cmath>
+#include <functional>
 
 using namespace distributed_point_functions;
 
@@ -24,8 +24,29 @@ struct ClientState {
     std::string encryption_context_info;
     int database_size;
     DpfParameters params;
+    // Set for clients created with pir_client_create_leader.
+    std::unique_ptr<DenseDpfPirClient> leader_client;
 };
 
+static DenseDpfPirClient::EncryptHelperRequestFn EncryptHelperRequest(
+    pir_encrypt_helper_request_fn encrypt, void* context) {
+    return [encrypt, context](absl::string_view plaintext,
+                              absl::string_view context_info) -> absl::StatusOr<std::string> {
+        uint8_t* ciphertext = nullptr;
+        size_t ciphertext_len = 0;
+        pir_status_t status = encrypt(
+            context,
+            reinterpret_cast<const uint8_t*>(plaintext.data()), plaintext.size(),
+            reinterpret_cast<const uint8_t*>(context_info.data()), context_info.size(),
+            &ciphertext, &ciphertext_len);
+        std::string encrypted = TakeBuffer(ciphertext, ciphertext_len);
+        if (status != PIR_SUCCESS) {
+            return absl::InternalError("encrypting the helper request failed");
+        }
+        return encrypted;
+    };
+}
+
 extern "C" {
 
 pir_status_t pir_client_create(int database_size, void** client_handle) {
@@ -66,9 +87,42 @@ pir_status_t pir_client_create(int database_size, void** client_handle) {
     }
 }
 
+pir_status_t pir_client_create_leader(int database_size,
+                                      pir_encrypt_helper_request_fn encrypt_helper_request,
+                                      void* context, void** client_handle) {
+    if (!encrypt_helper_request) {
+        return PIR_ERROR_INVALID_ARGUMENT;
+    }
+
+    void* handle = nullptr;
+    pir_status_t status = pir_client_create(database_size, &handle);
+    if (status != PIR_SUCCESS) {
+        return status;
+    }
+
+    try {
+        auto state = static_cast<ClientState*>(handle);
+        PirConfig config;
+        config.mutable_dense_dpf_pir_config()->set_num_elements(database_size);
+        auto status_or_client = DenseDpfPirClient::Create(
+            config, EncryptHelperRequest(encrypt_helper_request, context));
+        if (!status_or_client.ok()) {
+            pir_client_destroy(handle);
+            return PIR_ERROR_PROCESSING;
+        }
+        state->leader_client = std::move(status_or_client.value());
+
+        *client_handle = handle;
+        return PIR_SUCCESS;
+    } catch (const std::exception& e) {
+        pir_client_destroy(handle);
+        return PIR_ERROR_PROCESSING;
+    }
+}
+
 pir_status_t pir_client_generate_requests(void* client_handle, const int* indices, 
-                                        int num_indices, char** requests_json) {
-    if (!client_handle || !indices || num_indices <= 0 || !requests_json) {
+                                        int num_indices, uint8_t** requests, size_t* requests_len) {
+    if (!client_handle || !indices || num_indices <= 0 || !requests || !requests_len) {
         return PIR_ERROR_INVALID_ARGUMENT;
     }
 
@@ -108,23 +162,51 @@ pir_status_t pir_client_generate_requests(void* client_handle, const int* indice
             return PIR_ERROR_PROCESSING;
         }
 
-        // Base64 encode requests
-        std::string encoded_request1 = base64_encode(
-            reinterpret_cast<const unsigned char*>(serialized_request1.c_str()), 
-            serialized_request1.length());
-        std::string encoded_request2 = base64_encode(
-            reinterpret_cast<const unsigned char*>(serialized_request2.c_str()),
-            serialized_request2.length());
+        // One record per server
+        std::string encoded = EncodeRecords({serialized_request1, serialized_request2});
+        if (!CopyToBuffer(encoded, requests, requests_len)) {
+            return PIR_ERROR_MEMORY;
+        }
 
-        // Create JSON object
-        nlohmann::json j;
-        j["request1"] = encoded_request1;
-        j["request2"] = encoded_request2;
+        return PIR_SUCCESS;
+    } catch (const std::exception& e) {
+        return PIR_ERROR_PROCESSING;
+    }
+}
 
-        std::string json_str = j.dump();
-        *requests_json = strdup(json_str.c_str());
-        
-        if (!*requests_json) {
+pir_status_t pir_client_generate_leader_request(void* client_handle, const int* indices,
+                                               int num_indices, uint8_t** request,
+                                               size_t* request_len) {
+    if (!client_handle || !indices || num_indices <= 0 || !request || !request_len) {
+        return PIR_ERROR_INVALID_ARGUMENT;
+    }
+
+    try {
+        auto state = static_cast<ClientState*>(client_handle);
+        if (!state->leader_client) {
+            return PIR_ERROR_INVALID_ARGUMENT;
+        }
+        for (int i = 0; i < num_indices; i++) {
+            if (indices[i] < 0 || indices[i] >= state->database_size) {
+                return PIR_ERROR_INVALID_ARGUMENT;
+            }
+        }
+
+        auto status_or_request = state->leader_client->CreateRequest(
+            absl::MakeConstSpan(indices, num_indices));
+        if (!status_or_request.ok()) {
+            return PIR_ERROR_PROCESSING;
+        }
+
+        // The request for the leader and the state needed to read its response
+        std::string serialized_request, serialized_state;
+        if (!status_or_request.value().first.SerializeToString(&serialized_request) ||
+            !status_or_request.value().second.SerializeToString(&serialized_state)) {
+            return PIR_ERROR_PROCESSING;
+        }
+
+        std::string encoded = EncodeRecords({serialized_request, serialized_state});
+        if (!CopyToBuffer(encoded, request, request_len)) {
             return PIR_ERROR_MEMORY;
         }
 
@@ -134,64 +216,84 @@ pir_status_t pir_client_generate_requests(void* client_handle, const int* indice
     }
 }
 
-pir_status_t pir_client_process_responses(const char* responses_json, char** merged_result) {
-    if (!responses_json || !merged_result) {
+pir_status_t pir_client_process_leader_response(void* client_handle, const uint8_t* response,
+                                                size_t response_len, const uint8_t* client_state,
+                                                size_t client_state_len, uint8_t** records,
+                                                size_t* records_len) {
+    if (!client_handle || !response || !client_state || !records || !records_len) {
         return PIR_ERROR_INVALID_ARGUMENT;
     }
 
     try {
-        nlohmann::json responses_json_obj = nlohmann::json::parse(responses_json);
-        
-        if (!responses_json_obj.contains("response1") || !responses_json_obj.contains("response2")) {
+        auto state = static_cast<ClientState*>(client_handle);
+        if (!state->leader_client) {
             return PIR_ERROR_INVALID_ARGUMENT;
         }
 
-        std::string serialized_response1_base64 = responses_json_obj["response1"];
-        std::string serialized_response2_base64 = responses_json_obj["response2"];
-        
-        std::string serialized_response1 = base64_decode(serialized_response1_base64);
-        std::string serialized_response2 = base64_decode(serialized_response2_base64);
-        
-        PirResponse deserialized_response1, deserialized_response2;
-        if (!deserialized_response1.ParseFromString(serialized_response1) ||
-            !deserialized_response2.ParseFromString(serialized_response2)) {
+        PirResponse deserialized_response;
+        PirRequestClientState request_state;
+        if (!deserialized_response.ParseFromArray(response, static_cast<int>(response_len)) ||
+            !request_state.ParseFromArray(client_state, static_cast<int>(client_state_len))) {
             return PIR_ERROR_PROCESSING;
         }
 
-        // Process responses by XORing them together
-        if (deserialized_response1.dpf_pir_response().masked_response_size() !=
-            deserialized_response2.dpf_pir_response().masked_response_size()) {
+        auto status_or_records =
+            state->leader_client->HandleResponse(deserialized_response, request_state);
+        if (!status_or_records.ok()) {
             return PIR_ERROR_PROCESSING;
         }
 
-        std::vector<std::string> result;
-        for (int i = 0; i < deserialized_response1.dpf_pir_response().masked_response_size(); i++) {
-            if (deserialized_response1.dpf_pir_response().masked_response(i).size() !=
-                deserialized_response2.dpf_pir_response().masked_response(i).size()) {
+        if (!CopyToBuffer(EncodeRecords(status_or_records.value()), records, records_len)) {
+            return PIR_ERROR_MEMORY;
+        }
+
+        return PIR_SUCCESS;
+    } catch (const std::exception& e) {
+        return PIR_ERROR_PROCESSING;
+    }
+}
+
+pir_status_t pir_client_process_responses(const uint8_t* responses, size_t responses_len,
+                                          uint8_t** records, size_t* records_len) {
+    if (!responses || !records || !records_len) {
+        return PIR_ERROR_INVALID_ARGUMENT;
+    }
+
+    try {
+        std::vector<std::string> serialized_responses;
+        if (!DecodeRecords(responses, responses_len, &serialized_responses) ||
+            serialized_responses.size() != 2) {
+            return PIR_ERROR_INVALID_ARGUMENT;
+        }
+
+        std::vector<PirResponse> deserialized_responses(serialized_responses.size());
+        for (size_t i = 0; i < serialized_responses.size(); i++) {
+            if (!deserialized_responses[i].ParseFromString(serialized_responses[i])) {
                 return PIR_ERROR_PROCESSING;
             }
+        }
 
-            result.emplace_back(
-                deserialized_response1.dpf_pir_response().masked_response(i).size(), '\0');
-            
-            for (int j = 0; j < deserialized_response1.dpf_pir_response().masked_response(i).size(); ++j) {
-                result.back()[j] =
-                    deserialized_response1.dpf_pir_response().masked_response(i)[j] ^
-                    deserialized_response2.dpf_pir_response().masked_response(i)[j];
+        // Process responses by XORing them together
+        const auto& first = deserialized_responses[0].dpf_pir_response();
+        std::vector<std::string> result(first.masked_response().begin(),
+                                        first.masked_response().end());
+        for (size_t server = 1; server < deserialized_responses.size(); server++) {
+            const auto& response = deserialized_responses[server].dpf_pir_response();
+            if (response.masked_response_size() != first.masked_response_size()) {
+                return PIR_ERROR_PROCESSING;
             }
-        }
 
-        // Join results with commas
-        std::string final_result;
-        for (size_t i = 0; i < result.size(); ++i) {
-            final_result += result[i];
-            if (i < result.size() - 1) {
-                final_result += ", ";
+            for (int i = 0; i < response.masked_response_size(); i++) {
+                if (response.masked_response(i).size() != result[i].size()) {
+                    return PIR_ERROR_PROCESSING;
+                }
+                for (size_t j = 0; j < result[i].size(); ++j) {
+                    result[i][j] ^= response.masked_response(i)[j];
+                }
             }
         }
 
-        *merged_result = strdup(final_result.c_str());
-        if (!*merged_result) {
+        if (!CopyToBuffer(EncodeRecords(result), records, records_len)) {
             return PIR_ERROR_MEMORY;
         }
 
@@ -201,8 +303,8 @@ pir_status_t pir_client_process_responses(const char* responses_json, char** mer
     }
 }
 
-void pir_client_free_string(char* str) {
-    free(str);
+void pir_client_free_buffer(uint8_t* buffer) {
+    free(buffer);
 }
 
 void pir_client_destroy(void* client_handle) {
@@ -1,34 +1,88 @@
 #ifndef PIR_CLIENT_H
 #define PIR_CLIENT_H
 
+#include <stddef.h>
+#include <stdint.h>
+
 #ifdef __cplusplus
 extern "C" {
 #endif
 
 #include "status.h"
 
+// Encrypts the helper's share of a leader request for the helper under `context_info`.
+// `*ciphertext` must be allocated with malloc; the client releases it.
+typedef pir_status_t (*pir_encrypt_helper_request_fn)(
+    void* context,
+    const uint8_t* plaintext,
+    size_t plaintext_len,
+    const uint8_t* context_info,
+    size_t context_info_len,
+    uint8_t** ciphertext,
+    size_t* ciphertext_len
+);
+
 // Create a new PIR client instance
 pir_status_t pir_client_create(
     int database_size,
     void** client_handle
 );
 
-// Generate PIR requests for given indices
+// Create a client that sends a single request to a leader server (see PIR_SERVER_LEADER).
+// `context` is passed to `encrypt_helper_request` and must outlive the client.
+pir_status_t pir_client_create_leader(
+    int database_size,
+    pir_encrypt_helper_request_fn encrypt_helper_request,
+    void* context,
+    void** client_handle
+);
+
+// Generate PIR requests for given indices. On success `*requests` holds a length-prefixed
+// record array (see records.h) with one serialized request per server.
 pir_status_t pir_client_generate_requests(
     void* client_handle,
     const int* indices,
     int num_indices,
-    char** requests_json
+    uint8_t** requests,
+    size_t* requests_len
+);
+
+// Generate a single leader request for given indices, using a client created with
+// pir_client_create_leader. On success `*request` holds a record array of two records: the
+// serialized request for the leader and the client state needed to process its response.
+pir_status_t pir_client_generate_leader_request(
+    void* client_handle,
+    const int* indices,
+    int num_indices,
+    uint8_t** request,
+    size_t* request_len
+);
+
+// Process the leader's serialized response with the client state returned alongside the
+// request. On success `*records` holds a record array with one record per queried index.
+pir_status_t pir_client_process_leader_response(
+    void* client_handle,
+    const uint8_t* response,
+    size_t response_len,
+    const uint8_t* client_state,
+    size_t client_state_len,
+    uint8_t** records,
+    size_t* records_len
 );
 
-// Process responses from both servers
+// Process responses from the two servers, given as a record array with exactly two serialized
+// responses, whose masked responses are XORed together. DPF keys are shared between two servers
+// only; more servers are supported by the Rust `XorBackend`. On success `*records`
+// holds a record array with one record per queried index.
 pir_status_t pir_client_process_responses(
-    const char* responses_json,
-    char** merged_result
+    const uint8_t* responses,
+    size_t responses_len,
+    uint8_t** records,
+    size_t* records_len
 );
 
-// Free a string allocated by the PIR client system
-void pir_client_free_string(char* str);
+// Free a buffer allocated by the PIR client
+void pir_client_free_buffer(uint8_t* buffer);
 
 // Destroy a client instance
 void pir_client_destroy(void* client_handle);
@@ -37,4 +91,4 @@ void pir_client_destroy(void* client_handle);
 }
 #endif
 
-#endif // PIR_CLIENT_H
\ No newline at end of file
+#endif // PIR_CLIENT_H
@@ -1,8 +1,7 @@
 #include "client.h"
 #include "server.h"
+#include "records.h"
 #include "gtest/gtest.h"
-#include "nlohmann/json.hpp"
-#include "base64_utils.h"
 
 #include <vector>
 #include <string>
@@ -11,6 +10,15 @@
 namespace distributed_point_functions {
 namespace {
 
+const uint8_t* AsBytes(const std::string& s) {
+    return reinterpret_cast<const uint8_t*>(s.data());
+}
+
+pir_status_t create_server(const std::vector<std::string>& elements, void** server_handle) {
+    std::string encoded = EncodeRecords(elements);
+    return pir_server_create(AsBytes(encoded), encoded.size(), nullptr, server_handle);
+}
+
 pir_status_t create_test_server(int database_size, void** server_handle) {
     if (!server_handle) {
         return PIR_ERROR_INVALID_ARGUMENT;
@@ -22,31 +30,79 @@ pir_status_t create_test_server(int database_size, void** server_handle) {
         test_elements.push_back("Element " + std::to_string(i));
     }
 
-    std::vector<const char*> element_ptrs;
-    element_ptrs.reserve(database_size);
-    for (const auto& elem : test_elements) {
-        element_ptrs.push_back(elem.c_str());
-    }
+    return create_server(test_elements, server_handle);
+}
+
+// Queries `indices` from both servers and stores the reconstructed records in `results`.
+void Query(void* client, void* server1, void* server2, const std::vector<int>& indices,
+           std::vector<std::string>* results) {
+  // Generate one request per server
+  uint8_t* requests = nullptr;
+  size_t requests_len = 0;
+  pir_status_t status = pir_client_generate_requests(
+      client, indices.data(), indices.size(), &requests, &requests_len);
+  ASSERT_EQ(status, PIR_SUCCESS);
+  ASSERT_NE(requests, nullptr);
 
-    return pir_server_create(element_ptrs.data(), database_size, server_handle);
+  std::vector<std::string> request_records;
+  ASSERT_TRUE(DecodeRecords(requests, requests_len, &request_records));
+  ASSERT_EQ(request_records.size(), 2);
+  pir_client_free_buffer(requests);
+
+  // Get responses from both servers
+  void* servers[2] = {server1, server2};
+  std::vector<std::string> responses;
+  for (int i = 0; i < 2; i++) {
+    uint8_t* response = nullptr;
+    size_t response_len = 0;
+    status = pir_server_process_request(servers[i], AsBytes(request_records[i]),
+                                        request_records[i].size(), &response, &response_len);
+    ASSERT_EQ(status, PIR_SUCCESS);
+    ASSERT_NE(response, nullptr);
+    responses.emplace_back(reinterpret_cast<const char*>(response), response_len);
+    pir_server_free_buffer(response);
+  }
+
+  // Process responses
+  std::string encoded_responses = EncodeRecords(responses);
+  uint8_t* records = nullptr;
+  size_t records_len = 0;
+  status = pir_client_process_responses(AsBytes(encoded_responses), encoded_responses.size(),
+                                        &records, &records_len);
+  ASSERT_EQ(status, PIR_SUCCESS);
+  ASSERT_NE(records, nullptr);
+  ASSERT_TRUE(DecodeRecords(records, records_len, results));
+  pir_client_free_buffer(records);
+}
+
+// Test-only "encryption" of helper requests: XOR with a repeating byte, ignoring the context.
+pir_status_t XorCipher(void* context, const uint8_t* input, size_t input_len,
+                       const uint8_t* context_info, size_t context_info_len,
+                       uint8_t** output, size_t* output_len) {
+  const uint8_t key = *static_cast<const uint8_t*>(context);
+  std::string bytes(reinterpret_cast<const char*>(input), input_len);
+  for (char& c : bytes) {
+    c ^= key;
+  }
+  return CopyToBuffer(bytes, output, output_len) ? PIR_SUCCESS : PIR_ERROR_MEMORY;
+}
+
+// Forwards helper requests to the helper server passed as the context.
+pir_status_t ForwardToHelper(void* context, const uint8_t* request, size_t request_len,
+                             uint8_t** response, size_t* response_len) {
+  return pir_server_process_request(context, request, request_len, response, response_len);
 }
 
 class PirE2ETest : public ::testing::Test {
  protected:
   void SetUp() override {
     test_elements_ = {"Element0", "Element1", "Element2", "Element3"};
-    const char* elements[4] = {
-        test_elements_[0].c_str(),
-        test_elements_[1].c_str(),
-        test_elements_[2].c_str(),
-        test_elements_[3].c_str()
-    };
-    
+
     // Create two servers
-    pir_status_t status = pir_server_create(elements, test_elements_.size(), &server1_);
+    pir_status_t status = create_server(test_elements_, &server1_);
     ASSERT_EQ(status, PIR_SUCCESS);
-    
-    status = pir_server_create(elements, test_elements_.size(), &server2_);
+
+    status = create_server(test_elements_, &server2_);
     ASSERT_EQ(status, PIR_SUCCESS);
 
     // Create client
@@ -76,87 +132,17 @@ class PirE2ETest : public ::testing::Test {
 };
 
 TEST_F(PirE2ETest, SingleElementQuery) {
-  // Generate request for a single element
-  int index = 1;  // Query for "Element1"
-  char* requests = nullptr;
-  pir_status_t status = pir_client_generate_requests(client_, &index, 1, &requests);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(requests, nullptr);
-
-  // Parse requests JSON
-  nlohmann::json requests_json = nlohmann::json::parse(requests);
-  ASSERT_TRUE(requests_json.contains("request1"));
-  ASSERT_TRUE(requests_json.contains("request2"));
-
-  // Get responses from both servers
-  char* response1 = nullptr;
-  char* response2 = nullptr;
-  status = pir_server_process_request(server1_, requests_json["request1"].get<std::string>().c_str(), &response1);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response1, nullptr);
-
-  status = pir_server_process_request(server2_, requests_json["request2"].get<std::string>().c_str(), &response2);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response2, nullptr);
-
-  // Create response JSON
-  nlohmann::json response_json;
-  response_json["response1"] = response1;
-  response_json["response2"] = response2;
-
-  // Process responses
-  char* result = nullptr;
-  status = pir_client_process_responses(response_json.dump().c_str(), &result);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(result, nullptr);
-  EXPECT_EQ(std::string(result), "Element1");
-
-  // Cleanup
-  pir_client_free_string(requests);
-  pir_server_free_string(response1);
-  pir_server_free_string(response2);
-  pir_client_free_string(result);
+  // Query for "Element1"
+  std::vector<std::string> results;
+  Query(client_, server1_, server2_, {1}, &results);
+  EXPECT_EQ(results, std::vector<std::string>({"Element1"}));
 }
 
 TEST_F(PirE2ETest, MultiElementQuery) {
-  // Generate request for multiple elements
-  std::vector<int> indices = {0, 2};  // Query for "Element0" and "Element2"
-  char* requests = nullptr;
-  pir_status_t status = pir_client_generate_requests(client_, indices.data(), indices.size(), &requests);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(requests, nullptr);
-
-  // Parse requests JSON
-  nlohmann::json requests_json = nlohmann::json::parse(requests);
-
-  // Get responses from both servers
-  char* response1 = nullptr;
-  char* response2 = nullptr;
-  status = pir_server_process_request(server1_, requests_json["request1"].get<std::string>().c_str(), &response1);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response1, nullptr);
-
-  status = pir_server_process_request(server2_, requests_json["request2"].get<std::string>().c_str(), &response2);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response2, nullptr);
-
-  // Create response JSON
-  nlohmann::json response_json;
-  response_json["response1"] = response1;
-  response_json["response2"] = response2;
-
-  // Process responses
-  char* result = nullptr;
-  status = pir_client_process_responses(response_json.dump().c_str(), &result);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(result, nullptr);
-  EXPECT_EQ(std::string(result), "Element0, Element2");
-
-  // Cleanup
-  pir_client_free_string(requests);
-  pir_server_free_string(response1);
-  pir_server_free_string(response2);
-  pir_client_free_string(result);
+  // Query for "Element0" and "Element2"
+  std::vector<std::string> results;
+  Query(client_, server1_, server2_, {0, 2}, &results);
+  EXPECT_EQ(results, std::vector<std::string>({"Element0", "Element2"}));
 }
 
 TEST_F(PirE2ETest, GeneratedDataQuery) {
@@ -177,53 +163,130 @@ TEST_F(PirE2ETest, GeneratedDataQuery) {
   ASSERT_EQ(status, PIR_SUCCESS);
   ASSERT_NE(gen_client, nullptr);
 
-  // Generate request
-  int index = 5;
-  char* requests = nullptr;
-  status = pir_client_generate_requests(gen_client, &index, 1, &requests);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(requests, nullptr);
-
-  // Parse requests JSON
-  nlohmann::json requests_json = nlohmann::json::parse(requests);
-
-  // Get responses
-  char* response1 = nullptr;
-  char* response2 = nullptr;
-  status = pir_server_process_request(gen_server1, requests_json["request1"].get<std::string>().c_str(), &response1);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response1, nullptr);
-
-  status = pir_server_process_request(gen_server2, requests_json["request2"].get<std::string>().c_str(), &response2);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(response2, nullptr);
-
-  // Create response JSON
-  nlohmann::json response_json;
-  response_json["response1"] = response1;
-  response_json["response2"] = response2;
-
-  // Process responses
-  char* result = nullptr;
-  status = pir_client_process_responses(response_json.dump().c_str(), &result);
-  ASSERT_EQ(status, PIR_SUCCESS);
-  ASSERT_NE(result, nullptr);
-  EXPECT_EQ(std::string(result), "Element 5");
+  std::vector<std::string> results;
+  Query(gen_client, gen_server1, gen_server2, {5}, &results);
+  EXPECT_EQ(results, std::vector<std::string>({"Element 5"}));
 
   // Cleanup
-  pir_client_free_string(requests);
-  pir_server_free_string(response1);
-  pir_server_free_string(response2);
-  pir_client_free_string(result);
   pir_server_destroy(gen_server1);
   pir_server_destroy(gen_server2);
   pir_client_destroy(gen_client);
 }
 
+TEST_F(PirE2ETest, UpdateRows) {
+  int indices[2] = {1, 3};
+  std::string updated = EncodeRecords({"Updated1", "Updated3"});
+  ASSERT_EQ(pir_server_update_rows(server1_, indices, 2, AsBytes(updated), updated.size()), PIR_SUCCESS);
+  ASSERT_EQ(pir_server_update_rows(server2_, indices, 2, AsBytes(updated), updated.size()), PIR_SUCCESS);
+
+  // Out-of-range updates are rejected without touching the database.
+  int bad_index = 4;
+  std::string one = EncodeRecords({"Updated4"});
+  EXPECT_EQ(pir_server_update_rows(server1_, &bad_index, 1, AsBytes(one), one.size()),
+            PIR_ERROR_INVALID_ARGUMENT);
+
+  std::vector<std::string> results;
+  Query(client_, server1_, server2_, {0, 1, 3}, &results);
+  EXPECT_EQ(results, std::vector<std::string>({"Element0", "Updated1", "Updated3"}));
+}
+
+TEST_F(PirE2ETest, BinaryRecords) {
+  // Records may contain NUL bytes and commas.
+  std::string binary("a,\0b", 4);
+  int index = 2;
+  std::string encoded = EncodeRecords({binary});
+  ASSERT_EQ(pir_server_update_rows(server1_, &index, 1, AsBytes(encoded), encoded.size()), PIR_SUCCESS);
+  ASSERT_EQ(pir_server_update_rows(server2_, &index, 1, AsBytes(encoded), encoded.size()), PIR_SUCCESS);
+
+  std::vector<std::string> results;
+  Query(client_, server1_, server2_, {2}, &results);
+  ASSERT_EQ(results.size(), 1);
+  EXPECT_EQ(results[0].substr(0, binary.size()), binary);
+
+  // A single response cannot be reconstructed.
+  std::string single = EncodeRecords({""});
+  uint8_t* records = nullptr;
+  size_t records_len = 0;
+  EXPECT_EQ(pir_client_process_responses(AsBytes(single), single.size(), &records, &records_len),
+            PIR_ERROR_INVALID_ARGUMENT);
+
+  // Nor can more than two: DPF keys are only shared between two servers.
+  std::string three = EncodeRecords({"", "", ""});
+  EXPECT_EQ(pir_client_process_responses(AsBytes(three), three.size(), &records, &records_len),
+            PIR_ERROR_INVALID_ARGUMENT);
+
+  // Malformed record arrays are rejected.
+  void* server = nullptr;
+  uint8_t truncated[3] = {5, 0, 0};
+  EXPECT_EQ(pir_server_create(truncated, sizeof(truncated), nullptr, &server),
+            PIR_ERROR_INVALID_ARGUMENT);
+}
+
+TEST_F(PirE2ETest, LeaderHelperQuery) {
+  uint8_t key = 0x5a;
+  std::string encoded = EncodeRecords(test_elements_);
+
+  pir_server_options_t helper_options = {PIR_SERVER_HELPER, nullptr, XorCipher, &key};
+  void* helper = nullptr;
+  ASSERT_EQ(pir_server_create(AsBytes(encoded), encoded.size(), &helper_options, &helper),
+            PIR_SUCCESS);
+
+  pir_server_options_t leader_options = {PIR_SERVER_LEADER, ForwardToHelper, nullptr, helper};
+  void* leader = nullptr;
+  ASSERT_EQ(pir_server_create(AsBytes(encoded), encoded.size(), &leader_options, &leader),
+            PIR_SUCCESS);
+
+  // Leader mode needs its callback.
+  pir_server_options_t missing_callback = {PIR_SERVER_LEADER, nullptr, nullptr, nullptr};
+  void* server = nullptr;
+  EXPECT_EQ(pir_server_create(AsBytes(encoded), encoded.size(), &missing_callback, &server),
+            PIR_ERROR_INVALID_ARGUMENT);
+
+  void* client = nullptr;
+  ASSERT_EQ(pir_client_create_leader(test_elements_.size(), XorCipher, &key, &client),
+            PIR_SUCCESS);
+
+  // One request to the leader only
+  int indices[2] = {3, 1};
+  uint8_t* request = nullptr;
+  size_t request_len = 0;
+  ASSERT_EQ(pir_client_generate_leader_request(client, indices, 2, &request, &request_len),
+            PIR_SUCCESS);
+  std::vector<std::string> request_records;
+  ASSERT_TRUE(DecodeRecords(request, request_len, &request_records));
+  ASSERT_EQ(request_records.size(), 2);
+  pir_client_free_buffer(request);
+
+  uint8_t* response = nullptr;
+  size_t response_len = 0;
+  ASSERT_EQ(pir_server_process_request(leader, AsBytes(request_records[0]),
+                                       request_records[0].size(), &response, &response_len),
+            PIR_SUCCESS);
+
+  uint8_t* records = nullptr;
+  size_t records_len = 0;
+  ASSERT_EQ(pir_client_process_leader_response(client, response, response_len,
+                                               AsBytes(request_records[1]),
+                                               request_records[1].size(), &records, &records_len),
+            PIR_SUCCESS);
+  pir_server_free_buffer(response);
+
+  std::vector<std::string> results;
+  ASSERT_TRUE(DecodeRecords(records, records_len, &results));
+  pir_client_free_buffer(records);
+  ASSERT_EQ(results.size(), 2);
+  EXPECT_EQ(results[0].substr(0, test_elements_[3].size()), test_elements_[3]);
+  EXPECT_EQ(results[1].substr(0, test_elements_[1].size()), test_elements_[1]);
+
+  pir_server_destroy(leader);
+  pir_server_destroy(helper);
+  pir_client_destroy(client);
+}
+
 }  // namespace
 }  // namespace distributed_point_functions
 
 int main(int argc, char** argv) {
   ::testing::InitGoogleTest(&argc, argv);
   return RUN_ALL_TESTS();
-}
\ No newline at end of file
+}
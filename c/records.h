@@ -0,0 +1,71 @@
+#ifndef PIR_RECORDS_H
+#define PIR_RECORDS_H
+
+// Length-prefixed record arrays, the wire format of every byte buffer crossing the C ABI:
+// each record is a little-endian uint32 length followed by that many bytes, back to back.
+
+#include <cstdint>
+#include <cstdlib>
+#include <cstring>
+#include <string>
+#include <vector>
+
+// Splits `len` bytes at `data` into records. Returns false if the buffer is malformed.
+inline bool DecodeRecords(const uint8_t* data, size_t len, std::vector<std::string>* records) {
+    if (!data && len > 0) {
+        return false;
+    }
+    size_t offset = 0;
+    while (offset < len) {
+        if (len - offset < 4) {
+            return false;
+        }
+        uint32_t record_len = static_cast<uint32_t>(data[offset]) |
+                              static_cast<uint32_t>(data[offset + 1]) << 8 |
+                              static_cast<uint32_t>(data[offset + 2]) << 16 |
+                              static_cast<uint32_t>(data[offset + 3]) << 24;
+        offset += 4;
+        if (len - offset < record_len) {
+            return false;
+        }
+        records->emplace_back(reinterpret_cast<const char*>(data + offset), record_len);
+        offset += record_len;
+    }
+    return true;
+}
+
+inline std::string EncodeRecords(const std::vector<std::string>& records) {
+    std::string encoded;
+    for (const auto& record : records) {
+        uint32_t record_len = static_cast<uint32_t>(record.size());
+        for (int shift = 0; shift < 32; shift += 8) {
+            encoded.push_back(static_cast<char>((record_len >> shift) & 0xff));
+        }
+        encoded += record;
+    }
+    return encoded;
+}
+
+// Copies `bytes` into a malloc'd buffer that the caller releases with the library's
+// free_buffer function. Returns false if the allocation fails.
+inline bool CopyToBuffer(const std::string& bytes, uint8_t** buffer, size_t* buffer_len) {
+    *buffer = static_cast<uint8_t*>(malloc(bytes.empty() ? 1 : bytes.size()));
+    if (!*buffer) {
+        return false;
+    }
+    memcpy(*buffer, bytes.data(), bytes.size());
+    *buffer_len = bytes.size();
+    return true;
+}
+
+// Copies a malloc'd buffer returned by a caller-supplied callback and frees it.
+inline std::string TakeBuffer(uint8_t* buffer, size_t buffer_len) {
+    std::string bytes;
+    if (buffer) {
+        bytes.assign(reinterpret_cast<const char*>(buffer), buffer_len);
+    }
+    free(buffer);
+    return bytes;
+}
+
+#endif // PIR_RECORDS_H
@@ -3,7 +3,7 @@
 #include "pir/prng/aes_128_ctr_seeded_prng.h"
 #include "pir/dense_dpf_pir_database.h"
 #include "pir/dense_dpf_pir_server.h"
-#include "base64_utils.h"
+#include "records.h"
 
 #include <memory>
 #include <string>
@@ -11,6 +11,7 @@
 #include <mutex>
 #include <thread>
 #include <cmath>
+#include <functional>
 
 using namespace distributed_point_functions;
 
@@ -20,11 +21,13 @@ constexpr int kBitsPerBlock = 128;
 // Internal server state structure
 struct ServerState {
     std::unique_ptr<DenseDpfPirServer> server;
-    std::unique_ptr<DenseDpfPirServer::Database> database;
+    // The database `server` answers from, owned by `server`; updated in place.
+    DenseDpfPirDatabase* database = nullptr;
     std::unique_ptr<DistributedPointFunction> dpf;
     std::vector<std::string> elements;
     PirConfig config;
     DpfParameters params;
+    pir_server_options_t options;
 };
 
 // Helper function to create database from elements
@@ -40,16 +43,130 @@ CreateDatabase(const std::vector<typename Database::RecordType>& elements) {
     return builder->Build();
 }
 
+// Forwards the helper's share through the leader's callback. The leader's own share is computed
+// before the callback runs, since the callback blocks until the helper responds.
+static DenseDpfPirServer::ForwardHelperRequestFn ForwardHelperRequest(
+    const pir_server_options_t& options) {
+    return [options](const PirRequest& helper_request,
+                     std::function<void()> while_waiting) -> absl::StatusOr<PirResponse> {
+        std::string serialized_request;
+        if (!helper_request.SerializeToString(&serialized_request)) {
+            return absl::InternalError("failed to serialize helper request");
+        }
+        while_waiting();
+
+        uint8_t* response = nullptr;
+        size_t response_len = 0;
+        pir_status_t status = options.forward_helper_request(
+            options.callback_context,
+            reinterpret_cast<const uint8_t*>(serialized_request.data()), serialized_request.size(),
+            &response, &response_len);
+        std::string serialized_response = TakeBuffer(response, response_len);
+        if (status != PIR_SUCCESS) {
+            return absl::UnavailableError("forwarding the helper request failed");
+        }
+
+        PirResponse helper_response;
+        if (!helper_response.ParseFromString(serialized_response)) {
+            return absl::InvalidArgumentError("malformed helper response");
+        }
+        return helper_response;
+    };
+}
+
+static DenseDpfPirServer::DecryptHelperRequestFn DecryptHelperRequest(
+    const pir_server_options_t& options) {
+    return [options](absl::string_view ciphertext,
+                     absl::string_view context_info) -> absl::StatusOr<std::string> {
+        uint8_t* plaintext = nullptr;
+        size_t plaintext_len = 0;
+        pir_status_t status = options.decrypt_helper_request(
+            options.callback_context,
+            reinterpret_cast<const uint8_t*>(ciphertext.data()), ciphertext.size(),
+            reinterpret_cast<const uint8_t*>(context_info.data()), context_info.size(),
+            &plaintext, &plaintext_len);
+        std::string decrypted = TakeBuffer(plaintext, plaintext_len);
+        if (status != PIR_SUCCESS) {
+            return absl::InvalidArgumentError("decrypting the helper request failed");
+        }
+        return decrypted;
+    };
+}
+
+// Builds the database and server from state->elements, replacing the current server only on
+// success.
+static bool BuildServer(ServerState* state) {
+    auto status_or_database = CreateDatabase<DenseDpfPirDatabase>(state->elements);
+    if (!status_or_database.ok()) {
+        return false;
+    }
+    // The builder always produces a DenseDpfPirDatabase; keep a handle for in-place updates.
+    auto* database = static_cast<DenseDpfPirDatabase*>(status_or_database.value().get());
+
+    absl::StatusOr<std::unique_ptr<DenseDpfPirServer>> status_or_server;
+    switch (state->options.mode) {
+        case PIR_SERVER_LEADER:
+            status_or_server = DenseDpfPirServer::CreateLeader(
+                state->config, std::move(status_or_database.value()),
+                ForwardHelperRequest(state->options));
+            break;
+        case PIR_SERVER_HELPER:
+            status_or_server = DenseDpfPirServer::CreateHelper(
+                state->config, std::move(status_or_database.value()),
+                DecryptHelperRequest(state->options));
+            break;
+        default:
+            status_or_server = DenseDpfPirServer::CreatePlain(
+                state->config, std::move(status_or_database.value()));
+            break;
+    }
+    if (!status_or_server.ok()) {
+        return false;
+    }
+    state->server = std::move(status_or_server.value());
+    state->database = database;
+    return true;
+}
+
 extern "C" {
 
-pir_status_t pir_server_create(const char** elements, int num_elements, void** server_handle) {
-    if (!elements || num_elements <= 0 || !server_handle) {
+pir_status_t pir_server_create(const uint8_t* elements, size_t elements_len,
+                               const pir_server_options_t* options, void** server_handle) {
+    if (!elements || !server_handle) {
         return PIR_ERROR_INVALID_ARGUMENT;
     }
+    pir_server_options_t server_options = {PIR_SERVER_PLAIN, nullptr, nullptr, nullptr};
+    if (options) {
+        server_options = *options;
+    }
+    switch (server_options.mode) {
+        case PIR_SERVER_PLAIN:
+            break;
+        case PIR_SERVER_LEADER:
+            if (!server_options.forward_helper_request) {
+                return PIR_ERROR_INVALID_ARGUMENT;
+            }
+            break;
+        case PIR_SERVER_HELPER:
+            if (!server_options.decrypt_helper_request) {
+                return PIR_ERROR_INVALID_ARGUMENT;
+            }
+            break;
+        default:
+            return PIR_ERROR_INVALID_ARGUMENT;
+    }
 
     try {
+        std::vector<std::string> records;
+        if (!DecodeRecords(elements, elements_len, &records) || records.empty()) {
+            return PIR_ERROR_INVALID_ARGUMENT;
+        }
+        const int num_elements = static_cast<int>(records.size());
+
         auto state = new ServerState();
-        
+        state->elements = std::move(records);
+        state->options = server_options;
+
         // Setup config
         state->config.mutable_dense_dpf_pir_config()->set_num_elements(num_elements);
 
@@ -66,33 +183,53 @@ pir_status_t pir_server_create(const char** elements, int num_elements, void** s
         }
         state->dpf = std::move(status_or_dpf.value());
 
-        // Copy elements
-        state->elements.reserve(num_elements);
-        for (int i = 0; i < num_elements; i++) {
-            if (!elements[i]) {
-                delete state;
-                return PIR_ERROR_INVALID_ARGUMENT;
-            }
-            state->elements.push_back(elements[i]);
-        }
-
-        // Create database
-        auto status_or_database = CreateDatabase<DenseDpfPirDatabase>(state->elements);
-        if (!status_or_database.ok()) {
+        // Create database and server
+        if (!BuildServer(state)) {
             delete state;
             return PIR_ERROR_PROCESSING;
         }
-        state->database = std::move(status_or_database.value());
 
-        // Create server
-        auto status_or_server = DenseDpfPirServer::CreatePlain(state->config, std::move(state->database));
-        if (!status_or_server.ok()) {
-            delete state;
-            return PIR_ERROR_PROCESSING;
+        *server_handle = state;
+        return PIR_SUCCESS;
+
+    } catch (const std::exception& e) {
+        return PIR_ERROR_PROCESSING;
+    }
+}
+
+pir_status_t pir_server_update_rows(void* server_handle, const int* indices, int num_indices,
+                                    const uint8_t* elements, size_t elements_len) {
+    if (!server_handle || num_indices < 0 || (num_indices > 0 && !indices)) {
+        return PIR_ERROR_INVALID_ARGUMENT;
+    }
+
+    try {
+        auto state = static_cast<ServerState*>(server_handle);
+        std::vector<std::string> records;
+        if (!DecodeRecords(elements, elements_len, &records) ||
+            records.size() != static_cast<size_t>(num_indices)) {
+            return PIR_ERROR_INVALID_ARGUMENT;
+        }
+        const int num_elements = static_cast<int>(state->elements.size());
+        for (int i = 0; i < num_indices; i++) {
+            if (indices[i] < 0 || indices[i] >= num_elements) {
+                return PIR_ERROR_INVALID_ARGUMENT;
+            }
         }
-        state->server = std::move(status_or_server.value());
 
-        *server_handle = state;
+        // Replace the entries in the live database. state->elements still holds the old rows,
+        // so a failed update can be rolled back.
+        for (int i = 0; i < num_indices; i++) {
+            if (!state->database->UpdateEntry(indices[i], records[i]).ok()) {
+                for (int j = i - 1; j >= 0; j--) {
+                    state->database->UpdateEntry(indices[j], state->elements[indices[j]]).IgnoreError();
+                }
+                return PIR_ERROR_PROCESSING;
+            }
+        }
+        for (int i = 0; i < num_indices; i++) {
+            state->elements[indices[i]] = std::move(records[i]);
+        }
         return PIR_SUCCESS;
 
     } catch (const std::exception& e) {
@@ -100,20 +237,18 @@ pir_status_t pir_server_create(const char** elements, int num_elements, void** s
     }
 }
 
-pir_status_t pir_server_process_request(void* server_handle, const char* request_base64, char** response_base64) {
-    if (!server_handle || !request_base64 || !response_base64) {
+pir_status_t pir_server_process_request(void* server_handle, const uint8_t* request, size_t request_len,
+                                        uint8_t** response, size_t* response_len) {
+    if (!server_handle || !request || !response || !response_len) {
         return PIR_ERROR_INVALID_ARGUMENT;
     }
 
     try {
         auto state = static_cast<ServerState*>(server_handle);
 
-        // Decode base64 request
-        std::string serialized_request = base64_decode(request_base64);
-        
         // Deserialize request
         PirRequest deserialized_request;
-        if (!deserialized_request.ParseFromString(serialized_request)) {
+        if (!deserialized_request.ParseFromArray(request, static_cast<int>(request_len))) {
             return PIR_ERROR_PROCESSING;
         }
 
@@ -129,14 +264,8 @@ pir_status_t pir_server_process_request(void* server_handle, const char* request
             return PIR_ERROR_PROCESSING;
         }
 
-        // Encode to base64
-        std::string serialized_response_base64 = base64_encode(
-            reinterpret_cast<const unsigned char*>(serialized_response.data()),
-            serialized_response.size());
-
         // Allocate and copy response
-        *response_base64 = strdup(serialized_response_base64.c_str());
-        if (!*response_base64) {
+        if (!CopyToBuffer(serialized_response, response, response_len)) {
             return PIR_ERROR_MEMORY;
         }
 
@@ -154,8 +283,8 @@ void pir_server_destroy(void* server_handle) {
     }
 }
 
-void pir_server_free_string(char* str) {
-    free(str);
+void pir_server_free_buffer(uint8_t* buffer) {
+    free(buffer);
 }
 
 } // extern "C"
\ No newline at end of file
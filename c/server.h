@@ -1,28 +1,88 @@
 #ifndef PIR_SERVER_H
 #define PIR_SERVER_H
 
+#include <stddef.h>
+#include <stdint.h>
+
 #ifdef __cplusplus
 extern "C" {
 #endif
 
 #include "status.h"
 
-// Create a new PIR server with provided elements
+typedef enum {
+    // Answers plain requests; the client queries two servers itself.
+    PIR_SERVER_PLAIN = 0,
+    // Answers leader requests, forwarding the helper's encrypted share to the helper.
+    PIR_SERVER_LEADER = 1,
+    // Answers helper requests forwarded by the leader.
+    PIR_SERVER_HELPER = 2
+} pir_server_mode_t;
+
+// Sends a serialized helper request to the helper and waits for its serialized response.
+// `*response` must be allocated with malloc; the server releases it.
+typedef pir_status_t (*pir_forward_helper_request_fn)(
+    void* context,
+    const uint8_t* request,
+    size_t request_len,
+    uint8_t** response,
+    size_t* response_len
+);
+
+// Decrypts the helper's share of a leader request, encrypted by the client under
+// `context_info`. `*plaintext` must be allocated with malloc; the server releases it.
+typedef pir_status_t (*pir_decrypt_helper_request_fn)(
+    void* context,
+    const uint8_t* ciphertext,
+    size_t ciphertext_len,
+    const uint8_t* context_info,
+    size_t context_info_len,
+    uint8_t** plaintext,
+    size_t* plaintext_len
+);
+
+typedef struct {
+    pir_server_mode_t mode;
+    // Required for PIR_SERVER_LEADER.
+    pir_forward_helper_request_fn forward_helper_request;
+    // Required for PIR_SERVER_HELPER.
+    pir_decrypt_helper_request_fn decrypt_helper_request;
+    // Passed to the callbacks; must outlive the server.
+    void* callback_context;
+} pir_server_options_t;
+
+// Create a new PIR server whose elements are the records of a length-prefixed record array
+// (see records.h) of `elements_len` bytes. A null `options` creates a plain server.
 pir_status_t pir_server_create(
-    const char** elements,
-    int num_elements, 
+    const uint8_t* elements,
+    size_t elements_len,
+    const pir_server_options_t* options,
     void** server_handle
 );
 
-// Process a PIR request
+// Replace the elements at `indices` with the records of `elements`, one record per index,
+// updating only those entries of the server's database. Nothing is changed if any index is out
+// of range or an update fails.
+pir_status_t pir_server_update_rows(
+    void* server_handle,
+    const int* indices,
+    int num_indices,
+    const uint8_t* elements,
+    size_t elements_len
+);
+
+// Process a serialized PIR request. On success `*response` holds the serialized response,
+// to be released with pir_server_free_buffer.
 pir_status_t pir_server_process_request(
     void* server_handle,
-    const char* request_base64,
-    char** response_base64
+    const uint8_t* request,
+    size_t request_len,
+    uint8_t** response,
+    size_t* response_len
 );
 
-// Free a string allocated by the PIR server
-void pir_server_free_string(char* str);
+// Free a buffer allocated by the PIR server
+void pir_server_free_buffer(uint8_t* buffer);
 
 // Destroy a server instance
 void pir_server_destroy(void* server_handle);
@@ -31,4 +91,4 @@ void pir_server_destroy(void* server_handle);
 }
 #endif
 
-#endif // PIR_SERVER_H
\ No newline at end of file
+#endif // PIR_SERVER_H
@@ -0,0 +1,144 @@
+//! Pseudorandom functions that map a topic key and sequence number to a bucket.
+
+use aes::cipher::BlockEncrypt;
+use aes::Aes128;
+use hmac::Mac;
+
+use crate::{Error, HmacSha256};
+
+/// Derives the raw value behind a candidate bucket; callers reduce it modulo the bucket count.
+pub trait BucketPrf {
+    fn evaluate(&self, key: &[u8], seq_no: u64) -> Result<usize, Error>;
+}
+
+/// HMAC-SHA256 truncated to 8 bytes. Accepts keys of any length.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HmacSha256Prf;
+
+/// A single AES-128 block encryption of the sequence number. Requires a 16-byte key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aes128Prf;
+
+/// Keyed BLAKE3 of the sequence number. Keys of any length are first expanded to the 32 bytes
+/// BLAKE3 requires with its key derivation mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Prf;
+
+const BLAKE3_KEY_CONTEXT: &str = "dpf-rs cuckoo bucket PRF v1 blake3 key";
+
+impl BucketPrf for HmacSha256Prf {
+    fn evaluate(&self, key: &[u8], seq_no: u64) -> Result<usize, Error> {
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| Error::HmacError(e.to_string()))?;
+        mac.update(&seq_no.to_be_bytes());
+        let result = mac.finalize();
+        let hash = result.into_bytes();
+        Ok(usize::from_be_bytes(hash[0..8].try_into().unwrap()))
+    }
+}
+
+impl BucketPrf for Aes128Prf {
+    fn evaluate(&self, key: &[u8], seq_no: u64) -> Result<usize, Error> {
+        let cipher = <Aes128 as aes::cipher::KeyInit>::new_from_slice(key).map_err(|_| Error::InvalidKeyLength)?;
+        let mut block = aes::Block::default();
+        block[..8].copy_from_slice(&seq_no.to_be_bytes());
+        cipher.encrypt_block(&mut block);
+        Ok(usize::from_be_bytes(block[0..8].try_into().unwrap()))
+    }
+}
+
+impl BucketPrf for Blake3Prf {
+    fn evaluate(&self, key: &[u8], seq_no: u64) -> Result<usize, Error> {
+        let key = blake3::derive_key(BLAKE3_KEY_CONTEXT, key);
+        let hash = blake3::keyed_hash(&key, &seq_no.to_be_bytes());
+        Ok(usize::from_be_bytes(hash.as_bytes()[0..8].try_into().unwrap()))
+    }
+}
+
+/// The PRF a table derives buckets with; selectable per [`crate::Table`] and persisted in
+/// snapshots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrfKind {
+    #[default]
+    HmacSha256,
+    Aes128,
+    Blake3,
+}
+
+impl PrfKind {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            PrfKind::HmacSha256 => 0,
+            PrfKind::Aes128 => 1,
+            PrfKind::Blake3 => 2,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(PrfKind::HmacSha256),
+            1 => Some(PrfKind::Aes128),
+            2 => Some(PrfKind::Blake3),
+            _ => None,
+        }
+    }
+}
+
+impl BucketPrf for PrfKind {
+    fn evaluate(&self, key: &[u8], seq_no: u64) -> Result<usize, Error> {
+        match self {
+            PrfKind::HmacSha256 => HmacSha256Prf.evaluate(key, seq_no),
+            PrfKind::Aes128 => Aes128Prf.evaluate(key, seq_no),
+            PrfKind::Blake3 => Blake3Prf.evaluate(key, seq_no),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY16: &[u8] = b"0123456789abcdef";
+    const ALL: [PrfKind; 3] = [PrfKind::HmacSha256, PrfKind::Aes128, PrfKind::Blake3];
+
+    #[test]
+    fn test_deterministic_and_seq_dependent() {
+        for kind in ALL {
+            assert_eq!(kind.evaluate(KEY16, 5).unwrap(), kind.evaluate(KEY16, 5).unwrap());
+            assert_ne!(kind.evaluate(KEY16, 5).unwrap(), kind.evaluate(KEY16, 6).unwrap());
+            assert_ne!(
+                kind.evaluate(KEY16, 5).unwrap(),
+                kind.evaluate(b"fedcba9876543210", 5).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_kinds_differ() {
+        let outputs: Vec<usize> = ALL.iter().map(|kind| kind.evaluate(KEY16, 1).unwrap()).collect();
+        assert_ne!(outputs[0], outputs[1]);
+        assert_ne!(outputs[1], outputs[2]);
+        assert_ne!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn test_hmac_matches_legacy_prf() {
+        for seq_no in 0..8 {
+            assert_eq!(crate::prf(KEY16, seq_no).unwrap(), HmacSha256Prf.evaluate(KEY16, seq_no).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_aes_requires_16_byte_key() {
+        assert!(matches!(Aes128Prf.evaluate(b"short", 0), Err(Error::InvalidKeyLength)));
+        assert!(Blake3Prf.evaluate(b"short", 0).is_ok());
+    }
+
+    #[test]
+    fn test_byte_round_trip() {
+        for kind in ALL {
+            assert_eq!(Some(kind), PrfKind::from_byte(kind.to_byte()));
+        }
+        assert_eq!(None, PrfKind::from_byte(3));
+    }
+}
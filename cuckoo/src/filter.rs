@@ -0,0 +1,249 @@
+//! A cuckoo filter sharing the bucket layout of [`Table`].
+//!
+//! Slots hold short fingerprints instead of full items, so the exported filter is a much smaller
+//! database that can be served over PIR next to the table itself. A client fetches the rows of
+//! its candidate buckets and checks them with [`CuckooFilter::row_contains`].
+
+use hmac::Mac;
+
+use crate::{Error, EvictionStrategy, HmacSha256, Item, Table};
+
+/// Longest supported fingerprint, the size of an HMAC-SHA256 tag.
+pub const MAX_FINGERPRINT_SIZE: usize = 32;
+
+pub struct CuckooFilter {
+    table: Table,
+    next_id: u64,
+}
+
+impl CuckooFilter {
+    pub fn new(
+        num_buckets: usize,
+        bucket_depth: usize,
+        fingerprint_size: usize,
+        rand_seed: u64,
+    ) -> Option<Self> {
+        if fingerprint_size == 0 || fingerprint_size > MAX_FINGERPRINT_SIZE {
+            return None;
+        }
+        let table = Table::new(
+            num_buckets,
+            bucket_depth,
+            fingerprint_size,
+            None,
+            rand_seed,
+            EvictionStrategy::RandomWalk,
+        )?;
+        Some(Self { table, next_id: 0 })
+    }
+
+    /// Derives the fingerprint published for `seq_no` under `key`.
+    ///
+    /// All-zero bytes mark an empty slot, so a fingerprint is never all zero.
+    pub fn fingerprint(key: &[u8], seq_no: u64, fingerprint_size: usize) -> Result<Vec<u8>, Error> {
+        if fingerprint_size == 0 || fingerprint_size > MAX_FINGERPRINT_SIZE {
+            return Err(Error::InvalidInput);
+        }
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| Error::HmacError(e.to_string()))?;
+        mac.update(b"fingerprint");
+        mac.update(&seq_no.to_be_bytes());
+        let mut fingerprint = mac.finalize().into_bytes()[..fingerprint_size].to_vec();
+        if fingerprint.iter().all(|&b| b == 0) {
+            fingerprint[fingerprint_size - 1] = 1;
+        }
+        Ok(fingerprint)
+    }
+
+    /// Adds `fingerprint` under `buckets`. Returns `false` if the filter is full, in which case
+    /// the filter is left unchanged (see [`Table::insert`]).
+    pub fn insert(&mut self, fingerprint: &[u8], buckets: Vec<usize>) -> Result<bool, Error> {
+        self.validate(fingerprint)?;
+        let item = Item::new(self.next_id, fingerprint.to_vec(), buckets);
+        self.next_id += 1;
+        Ok(self.table.insert(&item)?.is_none())
+    }
+
+    /// Whether `fingerprint` is stored in any of `buckets` or in the stash.
+    pub fn contains(&self, fingerprint: &[u8], buckets: &[usize]) -> bool {
+        let bucket_size = self.bucket_size();
+        buckets.iter().any(|&bucket| {
+            bucket < self.table.num_buckets
+                && Self::row_contains(
+                    &self.table.data[bucket * bucket_size..(bucket + 1) * bucket_size],
+                    fingerprint,
+                )
+        }) || self.table.stash().iter().any(|item| item.data == fingerprint)
+    }
+
+    /// Removes one copy of `fingerprint` stored under `buckets`, looking only in those buckets
+    /// and the stash. Returns whether one was found.
+    pub fn remove(&mut self, fingerprint: &[u8], buckets: &[usize]) -> bool {
+        let table = &self.table;
+        let id = buckets
+            .iter()
+            .find_map(|&bucket| {
+                table.bucket_slots(bucket).find_map(|i| {
+                    let loc = &table.index[i];
+                    let slot = &table.data[i * table.item_size..(i + 1) * table.item_size];
+                    (loc.filled && loc.buckets == buckets && slot == fingerprint).then_some(loc.id)
+                })
+            })
+            .or_else(|| {
+                let mut stash = table.stash().iter();
+                stash.find(|item| item.data == fingerprint && item.buckets == buckets).map(|item| item.id)
+            });
+        id.is_some_and(|id| self.table.remove(id, buckets).is_some())
+    }
+
+    /// Whether a bucket row (or stash row) fetched from the exported filter holds `fingerprint`.
+    pub fn row_contains(row: &[u8], fingerprint: &[u8]) -> bool {
+        !fingerprint.is_empty() && row.chunks_exact(fingerprint.len()).any(|slot| slot == fingerprint)
+    }
+
+    /// The filter as `num_buckets` rows of `bucket_size()` bytes.
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.table.data
+    }
+
+    /// The stash, laid out like [`Table::stash_data`].
+    pub fn stash_bytes(&self) -> Vec<u8> {
+        self.table.stash_data()
+    }
+
+    /// Buckets changed since the previous call; see [`Table::take_dirty_buckets`].
+    pub fn take_dirty_buckets(&mut self) -> Vec<usize> {
+        self.table.take_dirty_buckets()
+    }
+
+    /// Size of one exported bucket row in bytes.
+    pub fn bucket_size(&self) -> usize {
+        self.table.bucket_depth * self.table.item_size
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.table.num_buckets
+    }
+
+    pub fn fingerprint_size(&self) -> usize {
+        self.table.item_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Grows the filter to `new_num_buckets`, keeping every fingerprint in its buckets.
+    pub fn grow(&mut self, new_num_buckets: usize) -> Result<(), Error> {
+        *self = self.grown(new_num_buckets)?;
+        Ok(())
+    }
+
+    /// Like [`CuckooFilter::grow`], but returns the grown filter and leaves this one untouched.
+    pub fn grown(&self, new_num_buckets: usize) -> Result<Self, Error> {
+        if new_num_buckets < self.table.num_buckets {
+            return Err(Error::InvalidInput);
+        }
+        let table = self.table.resized(new_num_buckets, |item| item.buckets.clone())?;
+        Ok(Self { table, next_id: self.next_id })
+    }
+
+    fn validate(&self, fingerprint: &[u8]) -> Result<(), Error> {
+        if fingerprint.len() != self.table.item_size || fingerprint.iter().all(|&b| b == 0) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prf;
+
+    const KEY1: &[u8] = b"filter_key_1";
+    const KEY2: &[u8] = b"filter_key_2";
+    const FP_KEY: &[u8] = b"filter_fingerprint_key";
+    const FP_SIZE: usize = 4;
+
+    fn buckets(filter: &CuckooFilter, seq_no: u64) -> Vec<usize> {
+        vec![
+            prf(KEY1, seq_no).unwrap() % filter.num_buckets(),
+            prf(KEY2, seq_no).unwrap() % filter.num_buckets(),
+        ]
+    }
+
+    #[test]
+    fn test_insert_contains_remove() {
+        let mut filter = CuckooFilter::new(16, 4, FP_SIZE, 1).unwrap();
+        for seq_no in 0..20 {
+            let fp = CuckooFilter::fingerprint(FP_KEY, seq_no, FP_SIZE).unwrap();
+            assert!(filter.insert(&fp, buckets(&filter, seq_no)).unwrap());
+        }
+        assert_eq!(20, filter.len());
+
+        for seq_no in 0..20 {
+            let fp = CuckooFilter::fingerprint(FP_KEY, seq_no, FP_SIZE).unwrap();
+            assert!(filter.contains(&fp, &buckets(&filter, seq_no)));
+        }
+        let absent = CuckooFilter::fingerprint(FP_KEY, 1000, FP_SIZE).unwrap();
+        assert!(!filter.contains(&absent, &buckets(&filter, 1000)));
+
+        let fp = CuckooFilter::fingerprint(FP_KEY, 3, FP_SIZE).unwrap();
+        assert!(filter.remove(&fp, &buckets(&filter, 3)));
+        assert!(!filter.contains(&fp, &buckets(&filter, 3)));
+        assert!(!filter.remove(&fp, &buckets(&filter, 3)));
+        assert_eq!(19, filter.len());
+    }
+
+    #[test]
+    fn test_failed_insert_keeps_earlier_fingerprints() {
+        let mut filter = CuckooFilter::new(2, 1, FP_SIZE, 1).unwrap();
+        let mut inserted = Vec::new();
+        for seq_no in 0.. {
+            let fp = CuckooFilter::fingerprint(FP_KEY, seq_no, FP_SIZE).unwrap();
+            if !filter.insert(&fp, vec![0, 1]).unwrap() {
+                break;
+            }
+            inserted.push(fp);
+        }
+        assert_eq!(2 + crate::STASH_SIZE, inserted.len());
+        assert!(inserted.iter().all(|fp| filter.contains(fp, &[0, 1])));
+
+        // Fingerprints in the stash are found and removed too.
+        assert!(inserted.iter().all(|fp| filter.remove(fp, &[0, 1])));
+        assert!(filter.is_empty());
+    }
+
+    #[test]
+    fn test_exported_rows() {
+        let mut filter = CuckooFilter::new(8, 2, FP_SIZE, 1).unwrap();
+        assert_eq!(8 * 2 * FP_SIZE, filter.to_bytes().len());
+        assert!(filter.to_bytes().iter().all(|&b| b == 0));
+
+        let fp = CuckooFilter::fingerprint(FP_KEY, 5, FP_SIZE).unwrap();
+        let candidates = buckets(&filter, 5);
+        assert!(filter.insert(&fp, candidates.clone()).unwrap());
+
+        // A client holding only the rows of its candidate buckets finds the fingerprint.
+        let size = filter.bucket_size();
+        let rows: Vec<&[u8]> = candidates
+            .iter()
+            .map(|&b| &filter.to_bytes()[b * size..(b + 1) * size])
+            .collect();
+        assert!(rows.iter().any(|row| CuckooFilter::row_contains(row, &fp)));
+    }
+
+    #[test]
+    fn test_invalid_fingerprints() {
+        let mut filter = CuckooFilter::new(8, 2, FP_SIZE, 1).unwrap();
+        assert!(filter.insert(&[0; FP_SIZE], vec![0, 1]).is_err());
+        assert!(filter.insert(&[1; FP_SIZE + 1], vec![0, 1]).is_err());
+        assert!(CuckooFilter::new(8, 2, 0, 1).is_none());
+        assert!(CuckooFilter::fingerprint(FP_KEY, 0, MAX_FINGERPRINT_SIZE + 1).is_err());
+    }
+}
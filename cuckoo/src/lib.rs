@@ -1,18 +1,30 @@
-use hmac::{Hmac, Mac};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+
+use hmac::Hmac;
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
 use sha2::Sha256;
 use thiserror::Error;
 
+mod bucket_prf;
+mod filter;
+mod sharded;
+mod snapshot;
+mod storage;
+
+pub use bucket_prf::{Aes128Prf, Blake3Prf, BucketPrf, HmacSha256Prf, PrfKind};
+pub use filter::{CuckooFilter, MAX_FINGERPRINT_SIZE};
+pub use sharded::ShardedTable;
+pub use storage::Storage;
+
 const MAX_EVICTIONS: usize = 500;
+/// Maximum number of items kept in the overflow stash of a [`Table`].
+pub const STASH_SIZE: usize = 8;
 type HmacSha256 = Hmac<Sha256>;
 
+/// The default bucket PRF, HMAC-SHA256; see [`BucketPrf`] for the alternatives.
 pub fn prf(key: &[u8], seq_no: u64) -> Result<usize, Error> {
-    let mut mac = HmacSha256::new_from_slice(key)
-        .map_err(|e| Error::HmacError(e.to_string()))?;
-    mac.update(&seq_no.to_be_bytes());
-    let result = mac.finalize();
-    let hash = result.into_bytes();
-    Ok(usize::from_be_bytes(hash[0..8].try_into().unwrap()))
+    HmacSha256Prf.evaluate(key, seq_no)
 }
 
 
@@ -20,16 +32,15 @@ pub fn prf(key: &[u8], seq_no: u64) -> Result<usize, Error> {
 pub struct Item {
     pub id: u64,
     pub data: Vec<u8>,
-    pub bucket1: usize,
-    pub bucket2: usize,
+    /// Candidate buckets the item may be stored in, one per hash function.
+    pub buckets: Vec<usize>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct ItemLocation {
     id: u64,
     filled: bool,
-    bucket1: usize,
-    bucket2: usize,
+    buckets: Vec<usize>,
 }
 
 #[derive(Error, Debug)]
@@ -38,17 +49,67 @@ pub enum Error {
     InvalidInput,
     #[error("No space available after eviction")]
     NoSpaceAfterEviction,
+    #[error("Resize failed: not every item fits in the resized table")]
+    ResizeFailed,
     #[error("HMAC error: {0}")]
     HmacError(String),
+    #[error("Invalid PRF key length")]
+    InvalidKeyLength,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedSnapshotVersion(u32),
+    #[error("Snapshot checksum mismatch")]
+    SnapshotChecksumMismatch,
+}
+
+/// How [`Table::insert`] makes room when all candidate buckets of an item are full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionStrategy {
+    /// Evict a random victim and re-insert it elsewhere, up to `MAX_EVICTIONS` times.
+    #[default]
+    RandomWalk,
+    /// Breadth-first search for the shortest chain of moves, at most `max_depth` moves long,
+    /// that ends in a free slot. Nothing is moved unless such a chain is found.
+    Bfs { max_depth: usize },
+}
+
+/// Counters collected by [`Table::insert`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InsertStats {
+    /// Calls to `insert` that passed validation.
+    pub inserts: u64,
+    /// Items displaced by eviction walks, including walks that retry the stash.
+    pub total_evictions: u64,
+    /// Longest eviction chain seen by a single `insert`.
+    pub max_chain_length: usize,
+    /// Number of inserts keyed by the length of their eviction chain.
+    pub chain_length_histogram: BTreeMap<usize, u64>,
+    /// Inserts that left an item in the stash.
+    pub stashed: u64,
+    /// Inserts that handed an item back because the stash was full.
+    pub failed: u64,
 }
 
 pub struct Table {
     pub num_buckets: usize,
     pub bucket_depth: usize,
     pub item_size: usize,
-    pub data: Vec<u8>,
-    pub rng: StdRng,
+    /// Item slots in bucket order, in memory or memory-mapped (see [`Table::create_mapped`]).
+    pub data: Storage,
+    /// Drives bucket and victim choices. This is the generator behind `StdRng`, used directly
+    /// so its stream position can be persisted in snapshots.
+    pub rng: ChaCha12Rng,
     pub index: Vec<ItemLocation>,
+    /// PRF used by [`Table::buckets`] to derive candidate buckets.
+    pub prf: PrfKind,
+    pub strategy: EvictionStrategy,
+    stash: Vec<Item>,
+    stats: InsertStats,
+    /// Buckets whose bytes changed since the last [`Table::take_dirty_buckets`].
+    dirty: BTreeSet<usize>,
 }
 
 impl Table {
@@ -58,6 +119,7 @@ impl Table {
         item_size: usize,
         data: Option<Vec<u8>>,
         rand_seed: u64,
+        strategy: EvictionStrategy,
     ) -> Option<Self> {
         let expected_size = num_buckets * bucket_depth * item_size;
         let data = data
@@ -68,42 +130,213 @@ impl Table {
             num_buckets,
             bucket_depth,
             item_size,
-            data,
-            rng: StdRng::seed_from_u64(rand_seed),
+            data: Storage::Memory(data),
+            rng: ChaCha12Rng::seed_from_u64(rand_seed),
             index: vec![ItemLocation::default(); num_buckets * bucket_depth],
+            prf: PrfKind::default(),
+            strategy,
+            stash: Vec::with_capacity(STASH_SIZE),
+            stats: InsertStats::default(),
+            dirty: BTreeSet::new(),
         })
     }
 
-    pub fn insert(&mut self, item: &Item) -> Result<Option<Item>, Error> {
-        if item.data.len() != self.item_size {
-            println!("item.data.len()={}", item.data.len());
-            println!("self.item_size={}", self.item_size);
+    /// Selects the PRF used to derive candidate buckets.
+    pub fn with_prf(mut self, prf: PrfKind) -> Self {
+        self.prf = prf;
+        self
+    }
+
+    /// Candidate buckets for `seq_no`, one per key, derived with this table's PRF.
+    pub fn buckets(&self, keys: &[&[u8]], seq_no: u64) -> Result<Vec<usize>, Error> {
+        if self.num_buckets == 0 {
             return Err(Error::InvalidInput);
         }
+        keys.iter()
+            .map(|key| Ok(self.prf.evaluate(key, seq_no)? % self.num_buckets))
+            .collect()
+    }
 
-        let (first_bucket, other_bucket) = if self.rng.gen_bool(0.5) {
-            (item.bucket1, item.bucket2)
+    /// Inserts `item`, evicting other items along a random walk if its buckets are full.
+    ///
+    /// An item left homeless by the eviction walk is kept in the stash. Once the stash is full,
+    /// `item` is only placed along a complete chain of moves ending in a free slot, so no stored
+    /// item is displaced; if there is none, `Ok(Some(item))` hands `item` itself back and the
+    /// table is left unchanged.
+    pub fn insert(&mut self, item: &Item) -> Result<Option<Item>, Error> {
+        self.validate(item)?;
+
+        let evictions_before = self.stats.total_evictions;
+        let placed = if self.stash.len() < STASH_SIZE {
+            self.place(item)?
         } else {
-            (item.bucket2, item.bucket1)
+            self.place_without_loss(item)
         };
+        let chain_length = (self.stats.total_evictions - evictions_before) as usize;
+        self.stats.inserts += 1;
+        self.stats.max_chain_length = self.stats.max_chain_length.max(chain_length);
+        *self.stats.chain_length_histogram.entry(chain_length).or_default() += 1;
+
+        match placed {
+            None => {
+                self.retry_stash();
+                Ok(None)
+            }
+            Some(homeless) if self.stash.len() < STASH_SIZE => {
+                self.stats.stashed += 1;
+                self.stash.push(homeless);
+                Ok(None)
+            }
+            Some(homeless) => {
+                self.stats.failed += 1;
+                Ok(Some(homeless))
+            }
+        }
+    }
+
+    /// Overwrites the stored item with the same id in one of `item`'s candidate buckets (or the
+    /// stash) in place, without evicting anything. Inserts `item` if no such item exists.
+    pub fn upsert(&mut self, item: &Item) -> Result<Option<Item>, Error> {
+        self.validate(item)?;
+
+        let slot = item.candidate_buckets().into_iter().find_map(|bucket| {
+            self.bucket_slots(bucket)
+                .find(|&i| self.index[i].filled && self.index[i].id == item.id)
+        });
+        if let Some(slot) = slot {
+            let data_start = slot * self.item_size;
+            self.data[data_start..data_start + self.item_size].copy_from_slice(&item.data);
+            self.index[slot].buckets = item.buckets.clone();
+            self.touch(slot / self.bucket_depth);
+            return Ok(None);
+        }
+
+        if let Some(stashed) = self.stash.iter_mut().find(|stashed| stashed.id == item.id) {
+            *stashed = item.clone();
+            return Ok(None);
+        }
+
+        self.insert(item)
+    }
+
+    fn validate(&self, item: &Item) -> Result<(), Error> {
+        if item.data.len() != self.item_size {
+            return Err(Error::InvalidInput);
+        }
+        if item.buckets.is_empty() || item.buckets.iter().any(|&b| b >= self.num_buckets) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Number of stored items, including those in the stash.
+    pub fn len(&self) -> usize {
+        self.index.iter().filter(|loc| loc.filled).count() + self.stash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fraction of bucket slots that are occupied; stashed items are not counted.
+    pub fn load_factor(&self) -> f64 {
+        if self.index.is_empty() {
+            return 0.0;
+        }
+        let filled = self.index.iter().filter(|loc| loc.filled).count();
+        filled as f64 / self.index.len() as f64
+    }
+
+    /// Iterates over all stored items, bucket slots first and then the stash.
+    pub fn iter(&self) -> impl Iterator<Item = Item> + '_ {
+        (0..self.index.len())
+            .filter_map(|i| self.get_item(i))
+            .chain(self.stash.iter().cloned())
+    }
+
+    /// Entry `k` is the number of buckets holding exactly `k` items.
+    pub fn bucket_fill_histogram(&self) -> Vec<usize> {
+        let mut histogram = vec![0; self.bucket_depth + 1];
+        for bucket in self.index.chunks(self.bucket_depth.max(1)) {
+            histogram[bucket.iter().filter(|loc| loc.filled).count()] += 1;
+        }
+        histogram
+    }
+
+    /// Eviction and placement counters accumulated since the table was created.
+    pub fn insert_stats(&self) -> &InsertStats {
+        &self.stats
+    }
+
+    /// Returns the buckets whose contents changed since the previous call, in ascending order,
+    /// so a published copy of `data` can be updated row by row. The stash is not tracked.
+    pub fn take_dirty_buckets(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.dirty).into_iter().collect()
+    }
 
-        if self.try_insert_to_bucket(first_bucket, item) {
+    /// Items that could not be placed in any of their buckets.
+    pub fn stash(&self) -> &[Item] {
+        &self.stash
+    }
+
+    /// The stash laid out as `STASH_SIZE` slots of `item_size` bytes, zero-filled when empty.
+    pub fn stash_data(&self) -> Vec<u8> {
+        let mut data = vec![0; STASH_SIZE * self.item_size];
+        for (slot, item) in data.chunks_mut(self.item_size).zip(&self.stash) {
+            slot.copy_from_slice(&item.data);
+        }
+        data
+    }
+
+    fn place(&mut self, item: &Item) -> Result<Option<Item>, Error> {
+        let mut candidates = item.candidate_buckets();
+        candidates.shuffle(&mut self.rng);
+
+        if candidates.iter().any(|&bucket| self.try_insert_to_bucket(bucket, item)) {
             return Ok(None);
         }
 
-        let mut next_bucket = other_bucket;
+        match self.strategy {
+            EvictionStrategy::RandomWalk => self.random_walk(item, candidates[0]),
+            EvictionStrategy::Bfs { max_depth } => Ok(self.bfs(item, &candidates, max_depth)),
+        }
+    }
+
+    /// Places `item` without leaving any item homeless: in a free candidate slot, or along the
+    /// shortest chain of moves found by [`Table::bfs`]. Returns `item` if neither exists.
+    fn place_without_loss(&mut self, item: &Item) -> Option<Item> {
+        let candidates = item.candidate_buckets();
+        if candidates.iter().any(|&bucket| self.try_insert_to_bucket(bucket, item)) {
+            return None;
+        }
+        let max_depth = match self.strategy {
+            EvictionStrategy::RandomWalk => MAX_EVICTIONS,
+            EvictionStrategy::Bfs { max_depth } => max_depth,
+        };
+        self.bfs(item, &candidates, max_depth)
+    }
+
+    fn random_walk(&mut self, item: &Item, first_bucket: usize) -> Result<Option<Item>, Error> {
+        // Every candidate is full: walk randomly, never sending an evicted item straight back
+        // to the bucket it was just evicted from.
+        let mut next_bucket = first_bucket;
         let mut current_item = item.clone();
 
         for _ in 0..MAX_EVICTIONS {
             match self.insert_and_evict(next_bucket, &current_item)? {
                 (true, None) => return Ok(None),
                 (true, Some(evicted)) => {
+                    self.stats.total_evictions += 1;
+                    let choices: Vec<usize> = evicted
+                        .candidate_buckets()
+                        .into_iter()
+                        .filter(|&bucket| bucket != next_bucket)
+                        .collect();
                     current_item = evicted;
-                    next_bucket = if current_item.bucket1 == next_bucket {
-                        current_item.bucket2
-                    } else {
-                        current_item.bucket1
-                    };
+                    match choices.choose(&mut self.rng) {
+                        Some(&bucket) => next_bucket = bucket,
+                        None => return Ok(Some(current_item)),
+                    }
                 }
                 (false, Some(item)) => return Ok(Some(item)),
                 _ => unreachable!(),
@@ -112,25 +345,291 @@ impl Table {
         Ok(Some(current_item))
     }
 
-    pub fn get(&self, prf1: usize, prf2: usize) -> Option<Item> {
-        // Ensure the two bucket indices are different.
-        assert!(prf1 != prf2, "the two bucket indices cannot be the same");
+    /// Places `item` by shifting occupants along the shortest path to a free slot, found by a
+    /// breadth-first search over buckets. Returns `item` untouched if no path of at most
+    /// `max_depth` moves exists.
+    fn bfs(&mut self, item: &Item, candidates: &[usize], max_depth: usize) -> Option<Item> {
+        struct Node {
+            bucket: usize,
+            depth: usize,
+            // The node whose bucket this node's occupant moves out of, and the slot it left.
+            parent: Option<(usize, usize)>,
+        }
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut queue = VecDeque::new();
+        let mut visited: HashSet<usize> = candidates.iter().copied().collect();
+        for &bucket in candidates {
+            queue.push_back(nodes.len());
+            nodes.push(Node { bucket, depth: 0, parent: None });
+        }
+
+        while let Some(n) = queue.pop_front() {
+            if nodes[n].depth >= max_depth {
+                continue;
+            }
+            for slot in self.bucket_slots(nodes[n].bucket) {
+                let occupant = self.index[slot].buckets.clone();
+                for bucket in occupant {
+                    if bucket == nodes[n].bucket || !visited.insert(bucket) {
+                        continue;
+                    }
+                    let child = nodes.len();
+                    nodes.push(Node { bucket, depth: nodes[n].depth + 1, parent: Some((n, slot)) });
+
+                    if let Some(free) = self.bucket_slots(bucket).find(|&i| !self.index[i].filled) {
+                        // Shift occupants along the path, starting at the free end.
+                        let mut target = free;
+                        let mut node = child;
+                        while let Some((parent, source)) = nodes[node].parent {
+                            let moved = self.clear_slot(source).unwrap();
+                            self.write_slot(target, &moved);
+                            self.stats.total_evictions += 1;
+                            target = source;
+                            node = parent;
+                        }
+                        self.write_slot(target, item);
+                        return None;
+                    }
+                    queue.push_back(child);
+                }
+            }
+        }
+        Some(item.clone())
+    }
 
-        // Closure to search a given bucket for an item with the provided prf1 and prf2.
+    /// Looks up the item stored under exactly the candidate buckets `buckets`.
+    pub fn get(&self, buckets: &[usize]) -> Option<Item> {
+        // Closure to search a given bucket for an item with the provided candidate buckets.
         let search_bucket = |bucket: usize| -> Option<Item> {
-            let start_index = bucket * self.bucket_depth;
-            println!("start_index: {}", start_index);
-            let end_index = start_index + self.bucket_depth;
-            for i in start_index..end_index {
+            self.bucket_slots(bucket).find_map(|i| {
                 let slot = &self.index[i];
-                if slot.filled && slot.bucket1 == prf1 && slot.bucket2 == prf2 {
-                    return self.get_item(i);
+                if slot.filled && slot.buckets == buckets {
+                    self.get_item(i)
+                } else {
+                    None
+                }
+            })
+        };
+
+        buckets
+            .iter()
+            .find_map(|&bucket| search_bucket(bucket))
+            .or_else(|| self.stash.iter().find(|item| item.buckets == buckets).cloned())
+    }
+
+    /// Removes the item with the given id stored under `buckets`, zeroing its slot.
+    ///
+    /// Returns the removed item, or `None` if no such item is stored in the table or stash.
+    pub fn remove(&mut self, id: u64, buckets: &[usize]) -> Option<Item> {
+        let slot = buckets.iter().find_map(|&bucket| {
+            self.bucket_slots(bucket).find(|&i| {
+                let loc = &self.index[i];
+                loc.filled && loc.id == id && loc.buckets == buckets
+            })
+        });
+
+        match slot {
+            Some(slot) => {
+                let removed = self.clear_slot(slot);
+                self.retry_stash();
+                removed
+            }
+            None => {
+                let pos = self
+                    .stash
+                    .iter()
+                    .position(|item| item.id == id && item.buckets == buckets)?;
+                Some(self.stash.remove(pos))
+            }
+        }
+    }
+
+    /// Replaces the contents of the table with `items`, placing the whole set at once.
+    ///
+    /// Placement is a maximum bipartite matching of items to bucket slots, computed with
+    /// breadth-first augmenting paths, so it succeeds whenever any assignment exists and depends
+    /// only on the order of `items` (not on the RNG). Items that cannot be placed are returned
+    /// rather than stashed; the stash is left empty.
+    pub fn build_from<I>(&mut self, items: I) -> Result<Vec<Item>, Error>
+    where
+        I: IntoIterator<Item = Item>,
+    {
+        let items: Vec<Item> = items.into_iter().collect();
+        for item in &items {
+            self.validate(item)?;
+        }
+
+        let mut bucket_items: Vec<Vec<usize>> = vec![Vec::new(); self.num_buckets];
+        let mut unplaced = Vec::new();
+        for i in 0..items.len() {
+            if !self.augment(i, &items, &mut bucket_items) {
+                unplaced.push(i);
+            }
+        }
+
+        self.data.fill(0);
+        self.index.fill(ItemLocation::default());
+        self.data.touch(0..self.data.len());
+        self.dirty.extend(0..self.num_buckets);
+        self.stash.clear();
+        for (bucket, assigned) in bucket_items.iter().enumerate() {
+            for (offset, &i) in assigned.iter().enumerate() {
+                self.write_slot(bucket * self.bucket_depth + offset, &items[i]);
+            }
+        }
+
+        let mut items: Vec<Option<Item>> = items.into_iter().map(Some).collect();
+        Ok(unplaced.into_iter().filter_map(|i| items[i].take()).collect())
+    }
+
+    /// Searches for an augmenting path that gives item `i` a bucket, shifting previously
+    /// assigned items along the path. Returns whether the item was assigned.
+    fn augment(&self, i: usize, items: &[Item], bucket_items: &mut [Vec<usize>]) -> bool {
+        // For every reached bucket: the bucket it was reached from and the item that would move.
+        let mut parent: Vec<Option<(usize, usize)>> = vec![None; self.num_buckets];
+        let mut visited = vec![false; self.num_buckets];
+        let mut queue = VecDeque::new();
+        for bucket in items[i].candidate_buckets() {
+            visited[bucket] = true;
+            queue.push_back(bucket);
+        }
+
+        while let Some(bucket) = queue.pop_front() {
+            if bucket_items[bucket].len() < self.bucket_depth {
+                let mut target = bucket;
+                while let Some((from, moved)) = parent[target] {
+                    bucket_items[from].retain(|&j| j != moved);
+                    bucket_items[target].push(moved);
+                    target = from;
+                }
+                bucket_items[target].push(i);
+                return true;
+            }
+            for &j in &bucket_items[bucket] {
+                for next in items[j].candidate_buckets() {
+                    if !visited[next] {
+                        visited[next] = true;
+                        parent[next] = Some((bucket, j));
+                        queue.push_back(next);
+                    }
                 }
             }
-            None
+        }
+        false
+    }
+
+    /// Migrates every stored item into a table of `new_num_buckets` buckets.
+    ///
+    /// `remap` returns the candidate buckets of an item in the resized table. If any item cannot
+    /// be placed (including via the stash), the table is left unchanged and
+    /// [`Error::ResizeFailed`] is returned.
+    pub fn resize<F>(&mut self, new_num_buckets: usize, remap: F) -> Result<(), Error>
+    where
+        F: Fn(&Item) -> Vec<usize>,
+    {
+        let mut resized = self.resized(new_num_buckets, remap)?;
+
+        // A mapped table moves to a data file of the new size; see `Storage::replace`.
+        let Storage::Memory(data) = std::mem::take(&mut resized.data) else {
+            unreachable!("resized tables are built in memory")
+        };
+        self.data.replace(data)?;
+        resized.data = std::mem::take(&mut self.data);
+        *self = resized;
+        Ok(())
+    }
+
+    /// Like [`Table::resize`], but returns the resized table in memory and leaves this one
+    /// untouched, so a caller can resize several structures and commit only if all succeed.
+    pub fn resized<F>(&self, new_num_buckets: usize, remap: F) -> Result<Table, Error>
+    where
+        F: Fn(&Item) -> Vec<usize>,
+    {
+        let mut resized = Self {
+            num_buckets: new_num_buckets,
+            bucket_depth: self.bucket_depth,
+            item_size: self.item_size,
+            data: Storage::Memory(vec![0; new_num_buckets * self.bucket_depth * self.item_size]),
+            rng: self.rng.clone(),
+            index: vec![ItemLocation::default(); new_num_buckets * self.bucket_depth],
+            prf: self.prf,
+            strategy: self.strategy,
+            stash: Vec::with_capacity(STASH_SIZE),
+            stats: InsertStats::default(),
+            dirty: (0..new_num_buckets).collect(),
         };
 
-        search_bucket(prf1).or_else(|| search_bucket(prf2))
+        for mut item in self.iter() {
+            item.buckets = remap(&item);
+            if resized.insert(&item)?.is_some() {
+                return Err(Error::ResizeFailed);
+            }
+        }
+        resized.stats = self.stats.clone();
+        Ok(resized)
+    }
+
+    /// Removes every stored item for which `predicate` returns `true`, zeroing their slots.
+    ///
+    /// Returns the removed items in slot order.
+    pub fn remove_where<F>(&mut self, mut predicate: F) -> Vec<Item>
+    where
+        F: FnMut(&Item) -> bool,
+    {
+        let mut removed = Vec::new();
+        for i in 0..self.index.len() {
+            if self.get_item(i).is_some_and(|item| predicate(&item)) {
+                removed.extend(self.clear_slot(i));
+            }
+        }
+
+        let (stashed, kept) = std::mem::take(&mut self.stash)
+            .into_iter()
+            .partition(|item| predicate(item));
+        self.stash = kept;
+        removed.extend::<Vec<Item>>(stashed);
+
+        if !removed.is_empty() {
+            self.retry_stash();
+        }
+        removed
+    }
+
+    /// Tries to move stashed items back into the table, e.g. after slots were freed.
+    fn retry_stash(&mut self) {
+        for stashed in std::mem::take(&mut self.stash) {
+            match self.place(&stashed) {
+                Ok(None) => {}
+                Ok(Some(homeless)) => self.stash.push(homeless),
+                Err(_) => self.stash.push(stashed),
+            }
+        }
+    }
+
+    fn bucket_slots(&self, bucket_index: usize) -> std::ops::Range<usize> {
+        if bucket_index >= self.num_buckets {
+            return 0..0;
+        }
+        let start = bucket_index * self.bucket_depth;
+        start..start + self.bucket_depth
+    }
+
+    fn clear_slot(&mut self, item_index: usize) -> Option<Item> {
+        let item = self.get_item(item_index)?;
+        let data_start = item_index * self.item_size;
+        self.data[data_start..data_start + self.item_size].fill(0);
+        self.index[item_index] = ItemLocation::default();
+        self.touch(item_index / self.bucket_depth);
+        Some(item)
+    }
+
+    /// Records that `bucket` changed, for [`Table::take_dirty_buckets`] and for the next
+    /// [`Table::flush`] of a mapped table.
+    fn touch(&mut self, bucket: usize) {
+        let bucket_size = self.bucket_depth * self.item_size;
+        self.data.touch(bucket * bucket_size..(bucket + 1) * bucket_size);
+        self.dirty.insert(bucket);
     }
 
     fn try_insert_to_bucket(&mut self, bucket_index: usize, item: &Item) -> bool {
@@ -139,26 +638,30 @@ impl Table {
 
         for i in start..end {
             if !self.index[i].filled {
-                let data_start = i * self.item_size;
-                self.data[data_start..data_start + item.data.len()].copy_from_slice(&item.data);
-                self.index[i] = ItemLocation {
-                    id: item.id,
-                    filled: true,
-                    bucket1: item.bucket1,
-                    bucket2: item.bucket2,
-                };
+                self.write_slot(i, item);
                 return true;
             }
         }
         false
     }
 
+    fn write_slot(&mut self, item_index: usize, item: &Item) {
+        let data_start = item_index * self.item_size;
+        self.data[data_start..data_start + item.data.len()].copy_from_slice(&item.data);
+        self.touch(item_index / self.bucket_depth);
+        self.index[item_index] = ItemLocation {
+            id: item.id,
+            filled: true,
+            buckets: item.buckets.clone(),
+        };
+    }
+
     fn insert_and_evict(
         &mut self,
         bucket_index: usize,
         item: &Item,
     ) -> Result<(bool, Option<Item>), Error> {
-        if item.bucket1 != bucket_index && item.bucket2 != bucket_index {
+        if !item.buckets.contains(&bucket_index) {
             return Ok((false, Some(item.clone())));
         }
 
@@ -185,26 +688,31 @@ impl Table {
         Some(Item {
             id: self.index[item_index].id,
             data: self.data[data_start..data_start + self.item_size].to_vec(),
-            bucket1: self.index[item_index].bucket1,
-            bucket2: self.index[item_index].bucket2,
+            buckets: self.index[item_index].buckets.clone(),
         })
     }
 }
 
 impl Item {
-    pub fn new(id: u64, data: Vec<u8>, bucket1: usize, bucket2: usize) -> Self {
-        Self {
-            id,
-            data,
-            bucket1,
-            bucket2,
+    pub fn new(id: u64, data: Vec<u8>, buckets: Vec<usize>) -> Self {
+        Self { id, data, buckets }
+    }
+
+    /// The distinct candidate buckets, in the order they were first listed.
+    fn candidate_buckets(&self) -> Vec<usize> {
+        let mut candidates = Vec::with_capacity(self.buckets.len());
+        for &bucket in &self.buckets {
+            if !candidates.contains(&bucket) {
+                candidates.push(bucket);
+            }
         }
+        candidates
     }
 }
 
 impl PartialEq for Item {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id && self.bucket1 == other.bucket1 && self.bucket2 == other.bucket2
+        self.id == other.id && self.buckets == other.buckets
     }
 }
 
@@ -230,10 +738,17 @@ mod tests {
             TEST_ITEM_SIZE,
             None,
             RANDOM_SEED,
+            EvictionStrategy::RandomWalk,
         )
         .unwrap()
     }
 
+    fn is_stored(table: &Table, item: &Item) -> bool {
+        table.index.iter().any(|loc| {
+            loc.filled && loc.id == item.id && loc.buckets == item.buckets
+        }) || table.stash().contains(item)
+    }
+
     #[test]
     fn test_get_capacity() {
         let table = create_test_table(10, 2);
@@ -249,7 +764,6 @@ mod tests {
     #[test]
     fn test_basic() {
         let mut table = create_test_table(10, 2);
-        println!("table.data.len()={}", table.data.len());
         assert_eq!(0, table.index.iter().filter(|loc| loc.filled).count());
 
         let seq_no = 0;
@@ -257,12 +771,12 @@ mod tests {
         let bucket2 = prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets;
 
         // Test with invalid data size
-        let item = Item::new(1, vec![0, 0], bucket1, bucket2);
+        let item = Item::new(1, vec![0, 0], vec![bucket1, bucket2]);
         let result = table.insert(&item);
         assert!(result.is_err());
 
         // Test with valid data
-        let item = Item::new(1, get_bytes("value1"), bucket1, bucket2);
+        let item = Item::new(1, get_bytes("value1"), vec![bucket1, bucket2]);
         let result = table.insert(&item);
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
@@ -282,29 +796,25 @@ mod tests {
         let mut count = 0;
         let mut entries = Vec::with_capacity(capacity);
         let mut evicted = None;
-        let mut seq_no = 0u64;
 
-        let bucket1 = prf(TEST_KEY1, seq_no).unwrap() % table.num_buckets;
-        let bucket2 = prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets;
+        let bucket1 = prf(TEST_KEY1, 0).unwrap() % table.num_buckets;
+        let bucket2 = prf(TEST_KEY2, 0).unwrap() % table.num_buckets;
 
         loop {
             let id = rng.gen::<u64>();
             let val = get_bytes(&rng.gen::<u64>().to_string());
             
-            let item = Item::new(id, val, bucket1, bucket2);
-            let empty_item = Item::new(id, vec![], bucket1, bucket2);
+            let item = Item::new(id, val, vec![bucket1, bucket2]);
+            let empty_item = Item::new(id, vec![], vec![bucket1, bucket2]);
             entries.push(empty_item);
-            seq_no += 1;
 
             match table.insert(&item) {
                 Ok(None) => {
                     count += 1;
-                    let found = table.index.iter().any(|loc| {
-                        loc.filled && loc.id == id && loc.bucket1 == item.bucket1 && loc.bucket2 == item.bucket2
-                    });
+                    let found = is_stored(&table, &item);
                     assert!(found, "Insert() succeeded, but item not found in table");
 
-                    let actual_count = table.index.iter().filter(|loc| loc.filled).count();
+                    let actual_count = table.len();
                     assert_eq!(
                         count, actual_count,
                         "Number of successful inserts ({}) does not match actual elements ({})",
@@ -319,7 +829,7 @@ mod tests {
             }
         }
 
-        let actual_count = table.index.iter().filter(|loc| loc.filled).count();
+        let actual_count = table.len();
         assert_eq!(
             count, actual_count,
             "Number of successful inserts ({}) does not match actual elements ({})",
@@ -329,27 +839,18 @@ mod tests {
 
         for entry in entries {
             if Some(&entry) != evicted.as_ref() {
-                let found = table.index.iter().any(|loc| {
-                    loc.filled
-                        && loc.bucket1 == entry.bucket1
-                        && loc.bucket2 == entry.bucket2
-                        && loc.id == entry.id
-                });
+                let found = is_stored(&table, &entry);
                 assert!(
                     found,
                     "Cannot find element believed to be in table. item {} of {}",
                     count, max_count
                 );
 
-                for loc in table.index.iter_mut() {
-                    if loc.filled && loc.id == entry.id {
-                        loc.filled = false;
-                        count -= 1;
-                        break;
-                    }
-                }
+                let removed = table.remove(entry.id, &entry.buckets);
+                assert!(removed.is_some_and(|item| item == entry));
+                count -= 1;
 
-                let actual_count = table.index.iter().filter(|loc| loc.filled).count();
+                let actual_count = table.len();
                 assert_eq!(
                     count, actual_count,
                     "GetNumElements()={} returned value that didn't match expected={}",
@@ -358,7 +859,7 @@ mod tests {
             }
         }
 
-        let final_count = table.index.iter().filter(|loc| loc.filled).count();
+        let final_count = table.len();
         assert_eq!(
             0, final_count,
             "GetNumElements() returns {} when table should be empty",
@@ -374,17 +875,17 @@ mod tests {
         let bucket1 = prf(TEST_KEY1, seq_no).unwrap() % table.num_buckets;
         let bucket2 = prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets;
 
-        let item1 = Item::new(1, get_bytes("v"), bucket1, bucket2);
+        let item1 = Item::new(1, get_bytes("v"), vec![bucket1, bucket2]);
         let result = table.insert(&item1);
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
 
-        let item2 = Item::new(2, get_bytes("v"), bucket1, bucket2);
+        let item2 = Item::new(2, get_bytes("v"), vec![bucket1, bucket2]);
         let result = table.insert(&item2);
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
 
-        let item3 = Item::new(3, get_bytes("v"), bucket1, bucket2);
+        let item3 = Item::new(3, get_bytes("v"), vec![bucket1, bucket2]);
         let result = table.insert(&item3);
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
@@ -416,7 +917,7 @@ mod tests {
         let data = get_bytes("test_value");
         let bucket1 = prf(TEST_KEY1, seq_no).unwrap() % table.num_buckets;
         let bucket2 = prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets;
-        let item = Item::new(id, data, bucket1, bucket2);
+        let item = Item::new(id, data, vec![bucket1, bucket2]);
 
         // Insert the item.
         let result = table.insert(&item);
@@ -424,12 +925,447 @@ mod tests {
         assert!(result.unwrap().is_none());
 
         // Now, retrieve the item using its two distinct PRF bucket indices.
-        let retrieved = table.get(bucket1, bucket2);
+        let retrieved = table.get(&[bucket1, bucket2]);
         assert!(retrieved.is_some(), "Expected to retrieve the inserted item");
         let retrieved_item = retrieved.unwrap();
         assert_eq!(retrieved_item.id, item.id, "The retrieved item id does not match");
         assert_eq!(retrieved_item.data, item.data, "The retrieved item data does not match");
-        assert_eq!(retrieved_item.bucket1, item.bucket1, "Bucket1 does not match");
-        assert_eq!(retrieved_item.bucket2, item.bucket2, "Bucket2 does not match");
+        assert_eq!(retrieved_item.buckets, item.buckets, "Buckets do not match");
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut table = create_test_table(10, 2);
+        let bucket1 = prf(TEST_KEY1, 7).unwrap() % table.num_buckets;
+        let bucket2 = prf(TEST_KEY2, 7).unwrap() % table.num_buckets;
+        let item = Item::new(7, get_bytes("remove_me"), vec![bucket1, bucket2]);
+        assert!(table.insert(&item).unwrap().is_none());
+
+        // A mismatched id must not remove anything.
+        assert!(table.remove(8, &[bucket1, bucket2]).is_none());
+
+        let removed = table.remove(7, &[bucket1, bucket2]).expect("item should be removed");
+        assert_eq!(removed.data, item.data);
+        assert_eq!(0, table.index.iter().filter(|loc| loc.filled).count());
+        assert!(table.data.iter().all(|&b| b == 0), "removed slot must be zeroed");
+        assert!(table.get(&[bucket1, bucket2]).is_none());
+        assert!(table.remove(7, &[bucket1, bucket2]).is_none());
+    }
+
+    #[test]
+    fn test_remove_where() {
+        let mut table = create_test_table(10, 4);
+        for id in 0..6u64 {
+            let bucket1 = prf(TEST_KEY1, id).unwrap() % table.num_buckets;
+            let bucket2 = prf(TEST_KEY2, id).unwrap() % table.num_buckets;
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![bucket1, bucket2]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+
+        let mut removed: Vec<u64> = table.remove_where(|item| item.id % 2 == 0).iter().map(|item| item.id).collect();
+        removed.sort();
+        assert_eq!(removed, vec![0, 2, 4]);
+
+        let remaining: Vec<u64> = table.index.iter().filter(|loc| loc.filled).map(|loc| loc.id).collect();
+        assert_eq!(3, remaining.len());
+        assert!(remaining.iter().all(|id| id % 2 == 1));
+    }
+
+    #[test]
+    fn test_stash_holds_overflow() {
+        // A single bucket pair with room for two items, so every further insert overflows.
+        let mut table = create_test_table(2, 1);
+        for id in 0..(2 + STASH_SIZE) as u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none(), "item {} should be stored", id);
+        }
+        assert_eq!(STASH_SIZE, table.stash().len());
+        assert_eq!(2, table.index.iter().filter(|loc| loc.filled).count());
+
+        // Once the stash is full the new item is handed back and nothing stored is displaced.
+        let stored: Vec<Item> = table.iter().collect();
+        let overflow = Item::new(100, get_bytes("overflow"), vec![0, 1]);
+        assert_eq!(Some(100), table.insert(&overflow).unwrap().map(|item| item.id));
+        assert_eq!(stored, table.iter().collect::<Vec<_>>());
+
+        let stashed = table.stash()[0].clone();
+        assert!(table.get(&[0, 1]).is_some());
+        assert_eq!(&stashed.data[..], &table.stash_data()[..TEST_ITEM_SIZE]);
+        assert_eq!(STASH_SIZE * TEST_ITEM_SIZE, table.stash_data().len());
+    }
+
+    #[test]
+    fn test_stash_retried_after_remove() {
+        let mut table = create_test_table(2, 1);
+        for id in 0..3u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+        assert_eq!(1, table.stash().len());
+        let stashed_id = table.stash()[0].id;
+
+        // Freeing a slot moves the stashed item back into the table.
+        let in_table = table.index.iter().find(|loc| loc.filled).unwrap().id;
+        assert!(table.remove(in_table, &[0, 1]).is_some());
+        assert!(table.stash().is_empty());
+        assert!(table.index.iter().any(|loc| loc.filled && loc.id == stashed_id));
+
+        // Items can also be removed straight from the stash.
+        let item = Item::new(10, get_bytes("10"), vec![0, 1]);
+        assert!(table.insert(&item).unwrap().is_none());
+        let stashed_id = table.stash()[0].id;
+        assert!(table.remove(stashed_id, &[0, 1]).is_some());
+        assert!(table.stash().is_empty());
+    }
+
+    #[test]
+    fn test_d_ary_buckets() {
+        let keys: [&[u8]; 4] = [TEST_KEY1, TEST_KEY2, b"test_key_3_for_prf_computation", b"test_key_4_for_prf_computation"];
+        let mut table = create_test_table(20, 2);
+
+        let mut items = Vec::new();
+        for seq_no in 0..30u64 {
+            let buckets: Vec<usize> = keys.iter().map(|key| prf(key, seq_no).unwrap() % table.num_buckets).collect();
+            let item = Item::new(seq_no, get_bytes(&seq_no.to_string()), buckets);
+            assert!(table.insert(&item).unwrap().is_none());
+            items.push(item);
+        }
+
+        for item in &items {
+            let retrieved = table.get(&item.buckets).expect("item should be retrievable");
+            assert_eq!(retrieved.data, item.data);
+            let slot = table.index.iter().position(|loc| loc.filled && loc.id == item.id);
+            if let Some(slot) = slot {
+                assert!(item.buckets.contains(&(slot / table.bucket_depth)), "item stored outside its candidate buckets");
+            }
+        }
+
+        // Duplicate candidates are allowed and simply collapse to one bucket.
+        let item = Item::new(99, get_bytes("dup"), vec![3, 3, 3]);
+        table.remove_where(|_| true);
+        assert!(table.insert(&item).unwrap().is_none());
+        assert!(table.get(&[3, 3, 3]).is_some());
+
+        // Out-of-range and empty candidate lists are rejected.
+        assert!(table.insert(&Item::new(100, get_bytes("bad"), vec![0, 20])).is_err());
+        assert!(table.insert(&Item::new(101, get_bytes("bad"), vec![])).is_err());
+    }
+
+    #[test]
+    fn test_more_choices_raise_load_factor() {
+        fn fill(choices: usize) -> usize {
+            let mut table = create_test_table(64, 2);
+            let mut rng = StdRng::seed_from_u64(RANDOM_SEED);
+            let mut stored = 0;
+            loop {
+                let buckets = (0..choices).map(|_| rng.gen_range(0..table.num_buckets)).collect();
+                let item = Item::new(stored as u64, get_bytes("x"), buckets);
+                match table.insert(&item) {
+                    Ok(None) if table.stash().is_empty() => stored += 1,
+                    _ => return stored,
+                }
+            }
+        }
+
+        assert!(fill(4) > fill(2), "four candidate buckets should fit more items than two");
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut table = create_test_table(10, 2);
+        let mut items = Vec::new();
+        for seq_no in 0..12u64 {
+            let buckets = vec![
+                prf(TEST_KEY1, seq_no).unwrap() % table.num_buckets,
+                prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets,
+            ];
+            let item = Item::new(seq_no, get_bytes(&seq_no.to_string()), buckets);
+            assert!(table.insert(&item).unwrap().is_none());
+            items.push(item);
+        }
+
+        let hash = |item: &Item, num_buckets: usize| {
+            vec![
+                prf(TEST_KEY1, item.id).unwrap() % num_buckets,
+                prf(TEST_KEY2, item.id).unwrap() % num_buckets,
+            ]
+        };
+        // `resized` builds the new table without touching the old one.
+        let resized = table.resized(40, |item| hash(item, 40)).unwrap();
+        assert_eq!(10, table.num_buckets);
+        assert_eq!((40, items.len()), (resized.num_buckets, resized.len()));
+
+        table.resize(40, |item| hash(item, 40)).unwrap();
+        assert_eq!(40, table.num_buckets);
+        assert_eq!(40 * 2 * TEST_ITEM_SIZE, table.data.len());
+        assert_eq!(items.len(), table.len());
+        for item in &items {
+            let retrieved = table.get(&hash(item, 40)).expect("item should survive the resize");
+            assert_eq!(retrieved.data, item.data);
+        }
+    }
+
+    #[test]
+    fn test_failed_resize_leaves_table_unchanged() {
+        let mut table = create_test_table(10, 2);
+        for id in 0..12u64 {
+            let buckets = vec![id as usize % 10, (id as usize + 1) % 10];
+            let item = Item::new(id, get_bytes(&id.to_string()), buckets);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+        let data = table.data.to_vec();
+
+        // A single two-slot bucket plus the stash cannot hold twelve items.
+        assert!(matches!(table.resize(1, |_| vec![0]), Err(Error::ResizeFailed)));
+        assert_eq!(10, table.num_buckets);
+        assert_eq!(data, table.data);
+        assert_eq!(12, table.len());
+    }
+
+    #[test]
+    fn test_occupancy_stats() {
+        let mut table = create_test_table(4, 2);
+        assert!(table.is_empty());
+        assert_eq!(0.0, table.load_factor());
+        assert_eq!(vec![4, 0, 0], table.bucket_fill_histogram());
+
+        for id in 0..3u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+        assert_eq!(3, table.len());
+        assert_eq!(3.0 / 8.0, table.load_factor());
+
+        let histogram = table.bucket_fill_histogram();
+        assert_eq!(4, histogram.iter().sum::<usize>());
+        assert_eq!(3, histogram.iter().enumerate().map(|(k, n)| k * n).sum::<usize>());
+
+        let mut ids: Vec<u64> = table.iter().map(|item| item.id).collect();
+        ids.sort();
+        assert_eq!(vec![0, 1, 2], ids);
+    }
+
+    #[test]
+    fn test_dirty_buckets() {
+        let mut table = create_test_table(10, 2);
+        assert!(table.take_dirty_buckets().is_empty());
+
+        let item = Item::new(1, get_bytes("dirty"), vec![3, 7]);
+        table.insert(&item).unwrap();
+        let dirty = table.take_dirty_buckets();
+        assert_eq!(1, dirty.len());
+        assert!(item.buckets.contains(&dirty[0]));
+        assert!(table.take_dirty_buckets().is_empty());
+
+        // Only buckets reported dirty differ from the previously published copy.
+        let published = table.data.to_vec();
+        for id in 2..12u64 {
+            table.insert(&Item::new(id, get_bytes("x"), vec![id as usize % 10, 5])).unwrap();
+        }
+        table.remove(item.id, &item.buckets).unwrap();
+        let dirty = table.take_dirty_buckets();
+        let bucket_size = 2 * TEST_ITEM_SIZE;
+        for bucket in 0..10 {
+            let range = bucket * bucket_size..(bucket + 1) * bucket_size;
+            if published[range.clone()] != table.data[range] {
+                assert!(dirty.contains(&bucket), "bucket {bucket} changed but is not dirty");
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_stats() {
+        let mut table = create_test_table(2, 1);
+        for id in 0..4u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+
+        let stats = table.insert_stats();
+        assert_eq!(4, stats.inserts);
+        assert_eq!(2, stats.stashed);
+        assert_eq!(0, stats.failed);
+        assert!(stats.max_chain_length > 0);
+        assert_eq!(Some(&2), stats.chain_length_histogram.get(&0));
+        assert_eq!(4, stats.chain_length_histogram.values().sum::<u64>());
+        assert!(stats.total_evictions >= stats.max_chain_length as u64);
+    }
+
+    #[test]
+    fn test_table_buckets_use_selected_prf() {
+        let keys: [&[u8]; 2] = [b"0123456789abcdef", b"fedcba9876543210"];
+        for kind in [PrfKind::HmacSha256, PrfKind::Aes128, PrfKind::Blake3] {
+            let mut table = create_test_table(10, 2).with_prf(kind);
+            let buckets = table.buckets(&keys, 3).unwrap();
+            let expected: Vec<usize> = keys.iter().map(|k| kind.evaluate(k, 3).unwrap() % 10).collect();
+            assert_eq!(expected, buckets);
+
+            let item = Item::new(3, get_bytes("prf"), buckets.clone());
+            assert!(table.insert(&item).unwrap().is_none());
+            assert!(table.get(&buckets).is_some());
+        }
+    }
+
+    #[test]
+    fn test_upsert() {
+        let mut table = create_test_table(10, 2);
+        let bucket1 = prf(TEST_KEY1, 1).unwrap() % table.num_buckets;
+        let bucket2 = prf(TEST_KEY2, 1).unwrap() % table.num_buckets;
+
+        // Upserting an unknown id inserts it.
+        let item = Item::new(1, get_bytes("first"), vec![bucket1, bucket2]);
+        assert!(table.upsert(&item).unwrap().is_none());
+        assert_eq!(1, table.len());
+
+        let slot = table.index.iter().position(|loc| loc.filled).unwrap();
+        let evictions = table.insert_stats().total_evictions;
+        let inserts = table.insert_stats().inserts;
+
+        // Upserting the same id overwrites the slot in place.
+        let updated = Item::new(1, get_bytes("second"), vec![bucket1, bucket2]);
+        assert!(table.upsert(&updated).unwrap().is_none());
+        assert_eq!(1, table.len());
+        assert_eq!(slot, table.index.iter().position(|loc| loc.filled).unwrap());
+        assert_eq!(updated.data, table.get(&[bucket1, bucket2]).unwrap().data);
+        assert_eq!(evictions, table.insert_stats().total_evictions);
+        assert_eq!(inserts, table.insert_stats().inserts);
+
+        assert!(table.upsert(&Item::new(1, vec![0], vec![bucket1, bucket2])).is_err());
+    }
+
+    #[test]
+    fn test_upsert_stashed_item() {
+        let mut table = create_test_table(2, 1);
+        for id in 0..3u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+        let stashed_id = table.stash()[0].id;
+
+        let updated = Item::new(stashed_id, get_bytes("updated"), vec![0, 1]);
+        assert!(table.upsert(&updated).unwrap().is_none());
+        assert_eq!(3, table.len());
+        assert_eq!(updated.data, table.stash()[0].data);
+    }
+
+    fn max_load_factor(strategy: EvictionStrategy, choices: usize) -> f64 {
+        let mut table = Table::new(256, 4, 8, None, RANDOM_SEED, strategy).unwrap();
+        let mut rng = StdRng::seed_from_u64(RANDOM_SEED);
+        for id in 0.. {
+            let buckets = (0..choices).map(|_| rng.gen_range(0..table.num_buckets)).collect();
+            let item = Item::new(id, vec![1; 8], buckets);
+            if table.insert(&item).unwrap().is_some() || !table.stash().is_empty() {
+                break;
+            }
+        }
+        table.load_factor()
+    }
+
+    #[test]
+    fn test_bfs_places_items() {
+        let strategy = EvictionStrategy::Bfs { max_depth: 4 };
+        let mut table = Table::new(10, 2, TEST_ITEM_SIZE, None, RANDOM_SEED, strategy).unwrap();
+        let mut items = Vec::new();
+        for seq_no in 0..16u64 {
+            let buckets = vec![
+                prf(TEST_KEY1, seq_no).unwrap() % table.num_buckets,
+                prf(TEST_KEY2, seq_no).unwrap() % table.num_buckets,
+            ];
+            let item = Item::new(seq_no, get_bytes(&seq_no.to_string()), buckets);
+            assert!(table.insert(&item).unwrap().is_none());
+            items.push(item);
+        }
+        for item in &items {
+            assert!(table.iter().any(|stored| stored.id == item.id && stored.data == item.data));
+        }
+        for (slot, loc) in table.index.iter().enumerate().filter(|(_, loc)| loc.filled) {
+            assert!(loc.buckets.contains(&(slot / table.bucket_depth)));
+        }
+    }
+
+    #[test]
+    fn test_bfs_without_path_leaves_table_untouched() {
+        let strategy = EvictionStrategy::Bfs { max_depth: 4 };
+        let mut table = Table::new(2, 1, TEST_ITEM_SIZE, None, RANDOM_SEED, strategy).unwrap();
+        for id in 0..2u64 {
+            let item = Item::new(id, get_bytes(&id.to_string()), vec![0, 1]);
+            assert!(table.insert(&item).unwrap().is_none());
+        }
+        let data = table.data.to_vec();
+        let overflow = Item::new(2, get_bytes("2"), vec![0, 1]);
+        assert!(table.insert(&overflow).unwrap().is_none());
+        assert_eq!(data, table.data);
+        assert_eq!(&[overflow], table.stash());
+    }
+
+    #[test]
+    fn test_strategies_max_load_factor() {
+        let random_walk = max_load_factor(EvictionStrategy::RandomWalk, 2);
+        let bfs = max_load_factor(EvictionStrategy::Bfs { max_depth: 6 }, 2);
+        let shallow_bfs = max_load_factor(EvictionStrategy::Bfs { max_depth: 1 }, 2);
+
+        // Load factor reached before the first item has to be stashed.
+        assert!(bfs > 0.9, "bfs reached only {}", bfs);
+        assert!(bfs > random_walk, "bfs {} should beat random walk {}", bfs, random_walk);
+        assert!(shallow_bfs < bfs, "a deeper search should reach a higher load");
+    }
+
+    #[test]
+    fn test_build_from() {
+        let mut table = create_test_table(64, 4);
+        let mut rng = StdRng::seed_from_u64(RANDOM_SEED);
+        let items: Vec<Item> = (0..240u64)
+            .map(|id| {
+                let buckets = vec![rng.gen_range(0..64), rng.gen_range(0..64)];
+                Item::new(id, get_bytes(&id.to_string()), buckets)
+            })
+            .collect();
+
+        // Pre-existing contents are replaced.
+        assert!(table.insert(&Item::new(999, get_bytes("old"), vec![0, 1])).unwrap().is_none());
+
+        let unplaced = table.build_from(items.clone()).unwrap();
+        assert_eq!(items.len(), table.len() + unplaced.len());
+        assert!(table.stash().is_empty());
+        assert!(!table.iter().any(|item| item.id == 999));
+        for (slot, loc) in table.index.iter().enumerate().filter(|(_, loc)| loc.filled) {
+            assert!(loc.buckets.contains(&(slot / table.bucket_depth)));
+        }
+        for item in table.iter() {
+            assert_eq!(items[item.id as usize].data, item.data);
+        }
+
+        // The matching places at least as many items as one-by-one insertion, deterministically.
+        let mut incremental = create_test_table(64, 4);
+        let inserted = items
+            .iter()
+            .take_while(|item| incremental.insert(item).unwrap().is_none() && incremental.stash().is_empty())
+            .count();
+        assert!(table.len() >= inserted);
+
+        let mut rebuilt = create_test_table(64, 4);
+        rebuilt.build_from(items).unwrap();
+        assert_eq!(table.data, rebuilt.data);
+    }
+
+    #[test]
+    fn test_build_from_reports_unplaced() {
+        let mut table = create_test_table(2, 1);
+        let items: Vec<Item> = (0..4u64)
+            .map(|id| Item::new(id, get_bytes(&id.to_string()), vec![0, 1]))
+            .collect();
+        let unplaced = table.build_from(items).unwrap();
+        assert_eq!(vec![2, 3], unplaced.iter().map(|item| item.id).collect::<Vec<_>>());
+        assert_eq!(2, table.len());
+
+        // Finds the assignment even when it requires moving an earlier item.
+        let items = vec![
+            Item::new(0, get_bytes("0"), vec![0, 1]),
+            Item::new(1, get_bytes("1"), vec![0]),
+        ];
+        assert!(table.build_from(items).unwrap().is_empty());
+
+        let invalid = vec![Item::new(0, vec![0], vec![0])];
+        assert!(table.build_from(invalid).is_err());
     }
 }
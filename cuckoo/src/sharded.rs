@@ -0,0 +1,425 @@
+//! A cuckoo table that many threads can insert into at once.
+//!
+//! Buckets are split into contiguous ranges, one per shard, and each shard sits behind its own
+//! lock. An eviction walk holds at most one shard lock at a time: it places the item in flight,
+//! picks up the victim, releases the lock and only then moves on to the victim's next bucket,
+//! which may live in another shard. Walks therefore never wait on each other in a cycle.
+//!
+//! While a victim is between shards it is not visible to [`ShardedTable::get`]; readers that
+//! need a consistent view should wait for writers to finish (or use [`ShardedTable::into_table`]).
+
+use std::sync::{Mutex, MutexGuard};
+
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+
+use crate::{Error, EvictionStrategy, Item, ItemLocation, Table, MAX_EVICTIONS, STASH_SIZE};
+
+struct Shard {
+    /// First bucket owned by this shard; buckets are stored relative to it.
+    first_bucket: usize,
+    data: Vec<u8>,
+    index: Vec<ItemLocation>,
+    rng: ChaCha12Rng,
+}
+
+pub struct ShardedTable {
+    pub num_buckets: usize,
+    pub bucket_depth: usize,
+    pub item_size: usize,
+    buckets_per_shard: usize,
+    shards: Vec<Mutex<Shard>>,
+    stash: Mutex<Vec<Item>>,
+}
+
+impl ShardedTable {
+    /// Creates an empty table split into `num_shards` shards of (nearly) equal bucket ranges.
+    ///
+    /// Shard `i` seeds its generator with `rand_seed + i`, so single-threaded use is
+    /// reproducible.
+    pub fn new(
+        num_buckets: usize,
+        bucket_depth: usize,
+        item_size: usize,
+        num_shards: usize,
+        rand_seed: u64,
+    ) -> Option<Self> {
+        if num_shards == 0 || num_shards > num_buckets.max(1) {
+            return None;
+        }
+        let buckets_per_shard = num_buckets.div_ceil(num_shards).max(1);
+        let shards = (0..num_buckets.div_ceil(buckets_per_shard))
+            .map(|i| {
+                let first_bucket = i * buckets_per_shard;
+                let shard_buckets = buckets_per_shard.min(num_buckets - first_bucket);
+                Mutex::new(Shard {
+                    first_bucket,
+                    data: vec![0; shard_buckets * bucket_depth * item_size],
+                    index: vec![ItemLocation::default(); shard_buckets * bucket_depth],
+                    rng: ChaCha12Rng::seed_from_u64(rand_seed.wrapping_add(i as u64)),
+                })
+            })
+            .collect();
+
+        Some(Self {
+            num_buckets,
+            bucket_depth,
+            item_size,
+            buckets_per_shard,
+            shards,
+            stash: Mutex::new(Vec::with_capacity(STASH_SIZE)),
+        })
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Inserts `item`. A homeless item goes to the stash, and `Ok(Some(item))` means the stash
+    /// was full and that item, possibly one displaced by the walk, was not stored.
+    pub fn insert(&self, item: &Item) -> Result<Option<Item>, Error> {
+        self.validate(item)?;
+
+        match self.place(item) {
+            None => Ok(None),
+            Some(homeless) => {
+                let mut stash = self.lock_stash();
+                if stash.len() < STASH_SIZE {
+                    stash.push(homeless);
+                    Ok(None)
+                } else {
+                    Ok(Some(homeless))
+                }
+            }
+        }
+    }
+
+    /// Looks up the item stored under exactly the candidate buckets `buckets`.
+    pub fn get(&self, buckets: &[usize]) -> Option<Item> {
+        buckets
+            .iter()
+            .filter(|&&bucket| bucket < self.num_buckets)
+            .find_map(|&bucket| {
+                let shard = self.lock_shard(bucket);
+                self.bucket_slots(&shard, bucket).find_map(|i| {
+                    let slot = &shard.index[i];
+                    (slot.filled && slot.buckets == buckets).then(|| self.get_item(&shard, i))
+                })
+            })
+            .or_else(|| self.lock_stash().iter().find(|item| item.buckets == buckets).cloned())
+    }
+
+    /// Removes the item with the given id stored under `buckets`, zeroing its slot, and tries to
+    /// move stashed items into the freed space.
+    ///
+    /// Returns the removed item, if any, and the items that no longer fit in the stash after
+    /// the retry (because concurrent inserts filled it meanwhile); those are no longer stored.
+    pub fn remove(&self, id: u64, buckets: &[usize]) -> (Option<Item>, Vec<Item>) {
+        let removed = buckets
+            .iter()
+            .filter(|&&bucket| bucket < self.num_buckets)
+            .find_map(|&bucket| {
+                let mut shard = self.lock_shard(bucket);
+                let slot = self.bucket_slots(&shard, bucket).find(|&i| {
+                    let loc = &shard.index[i];
+                    loc.filled && loc.id == id && loc.buckets == buckets
+                })?;
+                let item = self.get_item(&shard, slot);
+                let data_start = slot * self.item_size;
+                shard.data[data_start..data_start + self.item_size].fill(0);
+                shard.index[slot] = ItemLocation::default();
+                Some(item)
+            });
+
+        match removed {
+            Some(item) => (Some(item), self.retry_stash()),
+            None => {
+                let mut stash = self.lock_stash();
+                let pos = stash.iter().position(|item| item.id == id && item.buckets == buckets);
+                (pos.map(|pos| stash.remove(pos)), Vec::new())
+            }
+        }
+    }
+
+    /// Number of stored items, including those in the stash.
+    pub fn len(&self) -> usize {
+        let filled: usize = self
+            .shards
+            .iter()
+            .map(|shard| lock(shard).index.iter().filter(|loc| loc.filled).count())
+            .sum();
+        filled + self.lock_stash().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Items that could not be placed in any of their buckets.
+    pub fn stash(&self) -> Vec<Item> {
+        self.lock_stash().clone()
+    }
+
+    /// The bucket data in the layout of [`Table::data`]: `num_buckets` rows of
+    /// `bucket_depth * item_size` bytes, in bucket order.
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.num_buckets * self.bucket_depth * self.item_size);
+        for shard in &self.shards {
+            data.extend_from_slice(&lock(shard).data);
+        }
+        data
+    }
+
+    /// Merges the shards into a single [`Table`] with the same contents and layout.
+    pub fn into_table(self, rand_seed: u64, strategy: EvictionStrategy) -> Table {
+        let mut table = Table::new(
+            self.num_buckets,
+            self.bucket_depth,
+            self.item_size,
+            Some(self.data()),
+            rand_seed,
+            strategy,
+        )
+        .expect("Table::new does not fail");
+        table.index = self
+            .shards
+            .into_iter()
+            .flat_map(|shard| into_inner(shard).index)
+            .collect();
+        table.stash = into_inner(self.stash);
+        table
+    }
+
+    fn validate(&self, item: &Item) -> Result<(), Error> {
+        if item.data.len() != self.item_size
+            || item.buckets.is_empty()
+            || item.buckets.iter().any(|&b| b >= self.num_buckets)
+        {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Stores `item` in a free slot of any candidate bucket, or else by a random walk.
+    fn place(&self, item: &Item) -> Option<Item> {
+        let candidates = item.candidate_buckets();
+        for &bucket in &candidates {
+            let mut shard = self.lock_shard(bucket);
+            if self.try_insert_to_bucket(&mut shard, bucket, item) {
+                return None;
+            }
+        }
+        self.random_walk(item, candidates[0])
+    }
+
+    /// Walks randomly from `first_bucket`, holding one shard lock per step. Returns the item
+    /// left homeless if no free slot was reached within `MAX_EVICTIONS` moves.
+    fn random_walk(&self, item: &Item, first_bucket: usize) -> Option<Item> {
+        let mut next_bucket = first_bucket;
+        let mut current_item = item.clone();
+
+        for _ in 0..MAX_EVICTIONS {
+            let mut shard = self.lock_shard(next_bucket);
+            if self.try_insert_to_bucket(&mut shard, next_bucket, &current_item) {
+                return None;
+            }
+
+            // The bucket is full: swap the item in flight with a random occupant.
+            let victim_slot = self.bucket_slots(&shard, next_bucket).start
+                + shard.rng.gen_range(0..self.bucket_depth);
+            let evicted = self.get_item(&shard, victim_slot);
+            self.write_slot(&mut shard, victim_slot, &current_item);
+
+            let choices: Vec<usize> = evicted
+                .candidate_buckets()
+                .into_iter()
+                .filter(|&bucket| bucket != next_bucket)
+                .collect();
+            let choice = choices.choose(&mut shard.rng).copied();
+            current_item = evicted;
+            match choice {
+                Some(bucket) => next_bucket = bucket,
+                None => return Some(current_item),
+            }
+        }
+        Some(current_item)
+    }
+
+    /// Tries to move stashed items back into the table. Returns the items left homeless once
+    /// the stash is full again, which inserts running meanwhile can cause.
+    fn retry_stash(&self) -> Vec<Item> {
+        let stashed = std::mem::take(&mut *self.lock_stash());
+        self.retry(stashed)
+    }
+
+    fn retry(&self, items: Vec<Item>) -> Vec<Item> {
+        let mut overflow = Vec::new();
+        for item in items {
+            if let Some(homeless) = self.place(&item) {
+                let mut stash = self.lock_stash();
+                if stash.len() < STASH_SIZE {
+                    stash.push(homeless);
+                } else {
+                    overflow.push(homeless);
+                }
+            }
+        }
+        overflow
+    }
+
+    fn lock_shard(&self, bucket: usize) -> MutexGuard<'_, Shard> {
+        lock(&self.shards[bucket / self.buckets_per_shard])
+    }
+
+    fn lock_stash(&self) -> MutexGuard<'_, Vec<Item>> {
+        lock(&self.stash)
+    }
+
+    /// Slot range of `bucket` within `shard`'s index.
+    fn bucket_slots(&self, shard: &Shard, bucket: usize) -> std::ops::Range<usize> {
+        let start = (bucket - shard.first_bucket) * self.bucket_depth;
+        start..start + self.bucket_depth
+    }
+
+    fn try_insert_to_bucket(&self, shard: &mut Shard, bucket: usize, item: &Item) -> bool {
+        match self.bucket_slots(shard, bucket).find(|&i| !shard.index[i].filled) {
+            Some(slot) => {
+                self.write_slot(shard, slot, item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_slot(&self, shard: &mut Shard, slot: usize, item: &Item) {
+        let data_start = slot * self.item_size;
+        shard.data[data_start..data_start + self.item_size].copy_from_slice(&item.data);
+        shard.index[slot] = ItemLocation {
+            id: item.id,
+            filled: true,
+            buckets: item.buckets.clone(),
+        };
+    }
+
+    fn get_item(&self, shard: &Shard, slot: usize) -> Item {
+        let data_start = slot * self.item_size;
+        Item {
+            id: shard.index[slot].id,
+            data: shard.data[data_start..data_start + self.item_size].to_vec(),
+            buckets: shard.index[slot].buckets.clone(),
+        }
+    }
+}
+
+/// Locks `mutex`, ignoring poisoning: every critical section leaves the shard consistent
+/// before it can panic.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn into_inner<T>(mutex: Mutex<T>) -> T {
+    mutex.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prf;
+
+    const ITEM_SIZE: usize = 16;
+
+    fn item(id: u64, num_buckets: usize) -> Item {
+        let buckets = vec![
+            prf(b"sharded_key_1", id).unwrap() % num_buckets,
+            prf(b"sharded_key_2", id).unwrap() % num_buckets,
+        ];
+        Item::new(id, id.to_le_bytes().repeat(ITEM_SIZE / 8), buckets)
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let num_buckets = 64;
+        let table = ShardedTable::new(num_buckets, 4, ITEM_SIZE, 8, 1).unwrap();
+        assert_eq!(8, table.num_shards());
+
+        // 200 items in 256 slots: enough load for eviction chains to cross shards.
+        std::thread::scope(|scope| {
+            for writer in 0..4u64 {
+                let table = &table;
+                scope.spawn(move || {
+                    for id in (writer * 50)..(writer + 1) * 50 {
+                        assert!(table.insert(&item(id, num_buckets)).unwrap().is_none());
+                    }
+                });
+            }
+        });
+
+        assert_eq!(200, table.len());
+        for id in 0..200 {
+            let expected = item(id, num_buckets);
+            let stored = table.get(&expected.buckets).unwrap();
+            assert_eq!(expected.buckets, stored.buckets);
+        }
+    }
+
+    #[test]
+    fn test_layout_matches_table() {
+        let num_buckets = 10;
+        let table = ShardedTable::new(num_buckets, 2, ITEM_SIZE, 3, 1).unwrap();
+        for id in 0..12 {
+            assert!(table.insert(&item(id, num_buckets)).unwrap().is_none());
+        }
+        let data = table.data();
+        assert_eq!(num_buckets * 2 * ITEM_SIZE, data.len());
+
+        // Every stored item sits in one of its buckets of the shared layout.
+        let bucket_size = 2 * ITEM_SIZE;
+        for id in 0..12 {
+            let expected = item(id, num_buckets);
+            let in_bucket = expected.buckets.iter().any(|&b| {
+                data[b * bucket_size..(b + 1) * bucket_size]
+                    .chunks(ITEM_SIZE)
+                    .any(|slot| slot == expected.data.as_slice())
+            });
+            assert!(in_bucket || table.stash().contains(&expected));
+        }
+
+        let merged = table.into_table(1, EvictionStrategy::RandomWalk);
+        assert_eq!(data, merged.data);
+        assert_eq!(12, merged.len());
+        for id in 0..12 {
+            assert!(merged.get(&item(id, num_buckets).buckets).is_some());
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let table = ShardedTable::new(8, 2, ITEM_SIZE, 2, 1).unwrap();
+        let first = item(1, 8);
+        assert!(table.insert(&first).unwrap().is_none());
+        assert_eq!((Some(first.clone()), vec![]), table.remove(first.id, &first.buckets));
+        assert!(table.get(&first.buckets).is_none());
+        assert!(table.remove(first.id, &first.buckets).0.is_none());
+        assert!(table.is_empty());
+
+        assert!(ShardedTable::new(4, 2, ITEM_SIZE, 0, 1).is_none());
+        assert!(table.insert(&Item::new(2, vec![0; ITEM_SIZE], vec![8])).is_err());
+    }
+
+    #[test]
+    fn test_retry_respects_stash_size() {
+        let table = ShardedTable::new(2, 1, ITEM_SIZE, 1, 1).unwrap();
+        let items: Vec<Item> = (0..=(2 + STASH_SIZE) as u64)
+            .map(|id| Item::new(id, vec![id as u8; ITEM_SIZE], vec![0, 1]))
+            .collect();
+        for item in &items[..2 + STASH_SIZE] {
+            assert!(table.insert(item).unwrap().is_none());
+        }
+
+        // An item taken out for a retry while inserts filled the stash is handed back.
+        let overflow = table.retry(vec![items[2 + STASH_SIZE].clone()]);
+        assert_eq!(1, overflow.len());
+        assert_eq!(STASH_SIZE, table.stash().len());
+        assert_eq!(2 + STASH_SIZE, table.len());
+    }
+}
@@ -0,0 +1,381 @@
+//! Binary snapshots of a [`Table`].
+//!
+//! All integers are little-endian. The layout (version 1) is:
+//!
+//! ```text
+//! magic "CKOO" | version: u32
+//! num_buckets: u64 | bucket_depth: u64 | item_size: u64 | prf: u8
+//! strategy: u8 (0 = random walk, 1 = BFS) | bfs max_depth: u64
+//! rng seed: [u8; 32] | rng stream: u64 | rng word position: u128
+//! index: num_buckets * bucket_depth slots of
+//!     filled: u8 [| id: u64 | num_candidates: u32 | candidates: u64 * num_candidates]
+//! data: num_buckets * bucket_depth * item_size bytes
+//! stash: count: u32, then per item id: u64 | num_candidates: u32 | candidates | item_size bytes
+//! checksum: SHA-256 of everything above
+//! ```
+//!
+//! Insert statistics are diagnostics and are not persisted; a restored table starts afresh.
+
+use std::io::{Read, Write};
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{Error, EvictionStrategy, InsertStats, Item, ItemLocation, PrfKind, Storage, Table, STASH_SIZE};
+
+const MAGIC: &[u8; 4] = b"CKOO";
+pub(crate) const VERSION: u32 = 1;
+const CHECKSUM_SIZE: usize = 32;
+
+impl Table {
+    /// Writes a snapshot of the table, including the RNG position, to `writer`.
+    pub fn write_snapshot<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(self.data.len() + self.index.len() * 32 + 128);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        self.put_header(&mut buf);
+        self.put_index(&mut buf);
+        buf.extend_from_slice(&self.data);
+        self.put_stash(&mut buf);
+        write_sealed(writer, &buf)
+    }
+
+    /// Restores a table from a snapshot written by [`Table::write_snapshot`].
+    ///
+    /// The restored table continues the RNG stream where the snapshot left off, so replicas
+    /// restored from the same snapshot place subsequent items identically.
+    pub fn read_snapshot<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        let mut r = open_sealed(&buf, MAGIC)?;
+
+        let header = r.header()?;
+        let index = r.index(&header)?;
+        let data = r.bytes(header.data_size()?)?.to_vec();
+        let stash = r.stash(&header)?;
+        if !r.buf.is_empty() {
+            return Err(Error::InvalidSnapshot("trailing bytes".to_string()));
+        }
+
+        Ok(header.into_table(Storage::Memory(data), index, stash))
+    }
+
+    /// Dimensions, PRF, strategy and RNG position.
+    pub(crate) fn put_header(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.num_buckets as u64);
+        put_u64(buf, self.bucket_depth as u64);
+        put_u64(buf, self.item_size as u64);
+        buf.push(self.prf.to_byte());
+        match self.strategy {
+            EvictionStrategy::RandomWalk => {
+                buf.push(0);
+                put_u64(buf, 0);
+            }
+            EvictionStrategy::Bfs { max_depth } => {
+                buf.push(1);
+                put_u64(buf, max_depth as u64);
+            }
+        }
+
+        buf.extend_from_slice(&self.rng.get_seed());
+        put_u64(buf, self.rng.get_stream());
+        buf.extend_from_slice(&self.rng.get_word_pos().to_le_bytes());
+    }
+
+    pub(crate) fn put_index(&self, buf: &mut Vec<u8>) {
+        for loc in &self.index {
+            buf.push(loc.filled as u8);
+            if loc.filled {
+                put_u64(buf, loc.id);
+                put_buckets(buf, &loc.buckets);
+            }
+        }
+    }
+
+    pub(crate) fn put_stash(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.stash.len() as u32).to_le_bytes());
+        for item in &self.stash {
+            put_u64(buf, item.id);
+            put_buckets(buf, &item.buckets);
+            buf.extend_from_slice(&item.data);
+        }
+    }
+}
+
+/// Everything in a snapshot that precedes the index.
+pub(crate) struct Header {
+    pub(crate) num_buckets: usize,
+    pub(crate) bucket_depth: usize,
+    pub(crate) item_size: usize,
+    prf: PrfKind,
+    strategy: EvictionStrategy,
+    rng: ChaCha12Rng,
+}
+
+impl Header {
+    pub(crate) fn data_size(&self) -> Result<usize, Error> {
+        self.num_slots()?
+            .checked_mul(self.item_size)
+            .ok_or_else(|| Error::InvalidSnapshot("table dimensions overflow".to_string()))
+    }
+
+    fn num_slots(&self) -> Result<usize, Error> {
+        self.num_buckets
+            .checked_mul(self.bucket_depth)
+            .ok_or_else(|| Error::InvalidSnapshot("table dimensions overflow".to_string()))
+    }
+
+    pub(crate) fn into_table(self, data: Storage, index: Vec<ItemLocation>, stash: Vec<Item>) -> Table {
+        Table {
+            num_buckets: self.num_buckets,
+            bucket_depth: self.bucket_depth,
+            item_size: self.item_size,
+            data,
+            rng: self.rng,
+            index,
+            prf: self.prf,
+            strategy: self.strategy,
+            stash,
+            stats: InsertStats::default(),
+            dirty: Default::default(),
+        }
+    }
+}
+
+/// Writes `body` followed by its SHA-256 checksum.
+pub(crate) fn write_sealed<W: Write>(writer: &mut W, body: &[u8]) -> Result<(), Error> {
+    writer.write_all(body)?;
+    writer.write_all(&Sha256::digest(body))?;
+    Ok(())
+}
+
+/// Checks the magic, checksum and version of a buffer written by [`write_sealed`] and returns a
+/// reader positioned after the version.
+pub(crate) fn open_sealed<'a>(buf: &'a [u8], magic: &[u8; 4]) -> Result<SnapshotReader<'a>, Error> {
+    if buf.len() < magic.len() + 4 + CHECKSUM_SIZE {
+        return Err(Error::InvalidSnapshot("snapshot is truncated".to_string()));
+    }
+
+    let (body, checksum) = buf.split_at(buf.len() - CHECKSUM_SIZE);
+    if body[..magic.len()] != magic[..] {
+        return Err(Error::InvalidSnapshot("bad magic".to_string()));
+    }
+    if Sha256::digest(body).as_slice() != checksum {
+        return Err(Error::SnapshotChecksumMismatch);
+    }
+
+    let mut r = SnapshotReader { buf: &body[magic.len()..] };
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(Error::UnsupportedSnapshotVersion(version));
+    }
+    Ok(r)
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_buckets(buf: &mut Vec<u8>, buckets: &[usize]) {
+    buf.extend_from_slice(&(buckets.len() as u32).to_le_bytes());
+    for &bucket in buckets {
+        put_u64(buf, bucket as u64);
+    }
+}
+
+pub(crate) struct SnapshotReader<'a> {
+    pub(crate) buf: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub(crate) fn header(&mut self) -> Result<Header, Error> {
+        let num_buckets = self.usize()?;
+        let bucket_depth = self.usize()?;
+        let item_size = self.usize()?;
+        let prf = PrfKind::from_byte(self.u8()?)
+            .ok_or_else(|| Error::InvalidSnapshot("unknown PRF".to_string()))?;
+        let tag = self.u8()?;
+        let max_depth = self.usize()?;
+        let strategy = match tag {
+            0 => EvictionStrategy::RandomWalk,
+            1 => EvictionStrategy::Bfs { max_depth },
+            _ => return Err(Error::InvalidSnapshot("unknown eviction strategy".to_string())),
+        };
+
+        let mut rng = ChaCha12Rng::from_seed(self.bytes(32)?.try_into().unwrap());
+        rng.set_stream(self.u64()?);
+        rng.set_word_pos(u128::from_le_bytes(self.bytes(16)?.try_into().unwrap()));
+
+        Ok(Header { num_buckets, bucket_depth, item_size, prf, strategy, rng })
+    }
+
+    pub(crate) fn index(&mut self, header: &Header) -> Result<Vec<ItemLocation>, Error> {
+        let num_slots = header.num_slots()?;
+        let mut index = Vec::with_capacity(num_slots.min(self.buf.len()));
+        for _ in 0..num_slots {
+            let loc = match self.u8()? {
+                0 => ItemLocation::default(),
+                1 => ItemLocation {
+                    id: self.u64()?,
+                    filled: true,
+                    buckets: self.buckets(header.num_buckets)?,
+                },
+                _ => return Err(Error::InvalidSnapshot("bad slot flag".to_string())),
+            };
+            index.push(loc);
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn stash(&mut self, header: &Header) -> Result<Vec<Item>, Error> {
+        let stash_len = self.u32()? as usize;
+        if stash_len > STASH_SIZE {
+            return Err(Error::InvalidSnapshot("stash too long".to_string()));
+        }
+        let mut stash = Vec::with_capacity(stash_len);
+        for _ in 0..stash_len {
+            let id = self.u64()?;
+            let buckets = self.buckets(header.num_buckets)?;
+            let data = self.bytes(header.item_size)?.to_vec();
+            stash.push(Item::new(id, data, buckets));
+        }
+        Ok(stash)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::InvalidSnapshot("snapshot is truncated".to_string()));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.u64()?)
+            .map_err(|_| Error::InvalidSnapshot("value does not fit in usize".to_string()))
+    }
+
+    fn buckets(&mut self, num_buckets: usize) -> Result<Vec<usize>, Error> {
+        let len = self.u32()? as usize;
+        let mut buckets = Vec::with_capacity(len.min(self.buf.len() / 8));
+        for _ in 0..len {
+            let bucket = self.usize()?;
+            if bucket >= num_buckets {
+                return Err(Error::InvalidSnapshot("bucket index out of range".to_string()));
+            }
+            buckets.push(bucket);
+        }
+        Ok(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{prf, EvictionStrategy};
+
+    const ITEM_SIZE: usize = 16;
+
+    fn filled_table() -> Table {
+        let mut table = Table::new(8, 2, ITEM_SIZE, None, 7, EvictionStrategy::Bfs { max_depth: 3 }).unwrap();
+        for seq_no in 0..20u64 {
+            let buckets = vec![
+                prf(b"snapshot_key_1", seq_no).unwrap() % table.num_buckets,
+                prf(b"snapshot_key_2", seq_no).unwrap() % table.num_buckets,
+            ];
+            let mut data = vec![0; ITEM_SIZE];
+            data[..8].copy_from_slice(&seq_no.to_le_bytes());
+            table.insert(&Item::new(seq_no, data, buckets)).unwrap();
+        }
+        table
+    }
+
+    fn snapshot(table: &Table) -> Vec<u8> {
+        let mut buf = Vec::new();
+        table.write_snapshot(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let table = filled_table().with_prf(PrfKind::Blake3);
+        let bytes = snapshot(&table);
+        let restored = Table::read_snapshot(bytes.as_slice()).unwrap();
+
+        assert_eq!(table.num_buckets, restored.num_buckets);
+        assert_eq!(table.bucket_depth, restored.bucket_depth);
+        assert_eq!(table.item_size, restored.item_size);
+        assert_eq!(PrfKind::Blake3, restored.prf);
+        assert_eq!(EvictionStrategy::Bfs { max_depth: 3 }, restored.strategy);
+        assert_eq!(table.data, restored.data);
+        assert_eq!(table.stash(), restored.stash());
+        for (a, b) in table.index.iter().zip(&restored.index) {
+            assert_eq!((a.filled, a.id, &a.buckets), (b.filled, b.id, &b.buckets));
+        }
+        assert_eq!(bytes, snapshot(&restored));
+    }
+
+    #[test]
+    fn test_restored_replicas_stay_in_lockstep() {
+        let bytes = snapshot(&filled_table());
+        let mut replica1 = Table::read_snapshot(bytes.as_slice()).unwrap();
+        let mut replica2 = Table::read_snapshot(bytes.as_slice()).unwrap();
+
+        for id in 100..110u64 {
+            let item = Item::new(id, vec![id as u8; ITEM_SIZE], vec![0, 1]);
+            replica1.insert(&item).unwrap();
+            replica2.insert(&item).unwrap();
+        }
+        assert_eq!(snapshot(&replica1), snapshot(&replica2));
+    }
+
+    #[test]
+    fn test_rejects_corruption() {
+        let mut bytes = snapshot(&filled_table());
+        let mid = bytes.len() / 2;
+        bytes[mid] ^= 0xff;
+        assert!(matches!(
+            Table::read_snapshot(bytes.as_slice()),
+            Err(Error::SnapshotChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            Table::read_snapshot(&b"CKOO"[..]),
+            Err(Error::InvalidSnapshot(_))
+        ));
+
+        let mut bytes = snapshot(&filled_table());
+        bytes[4..8].copy_from_slice(&99u32.to_le_bytes());
+        let len = bytes.len();
+        let checksum = Sha256::digest(&bytes[..len - CHECKSUM_SIZE]);
+        bytes[len - CHECKSUM_SIZE..].copy_from_slice(&checksum);
+        assert!(matches!(
+            Table::read_snapshot(bytes.as_slice()),
+            Err(Error::UnsupportedSnapshotVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_oversized_stash() {
+        let mut table = filled_table();
+        table.stash = (0..=STASH_SIZE as u64).map(|id| Item::new(id, vec![0; ITEM_SIZE], vec![0, 1])).collect();
+        assert!(matches!(
+            Table::read_snapshot(snapshot(&table).as_slice()),
+            Err(Error::InvalidSnapshot(_))
+        ));
+    }
+}
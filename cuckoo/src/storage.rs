@@ -0,0 +1,531 @@
+//! Backing storage for [`Table::data`].
+//!
+//! A table lives either in memory or in a memory-mapped data file. A mapped table keeps its
+//! index, stash and RNG position in a sidecar file next to the data file (the data path with
+//! `.index` appended), in the snapshot encoding minus the data section. The mapping is shared,
+//! so the data lives in the page cache rather than in process memory, and [`Table::flush`] only
+//! syncs the buckets changed since the previous flush before replacing the sidecar.
+//!
+//! The OS may write changed pages back at any time, so the data file ends in an 8-byte trailer
+//! pairing it with its sidecar: the generation of the last flush, which the sidecar records as
+//! well. Before the first change after a flush the trailer is overwritten with `CHANGED` and
+//! synced, and the flush that commits the changes writes the new generation once the sidecar is
+//! in place. [`Table::open_mapped`] only accepts a data file whose trailer matches its sidecar,
+//! so a crash between or during flushes is reported rather than restoring an index that points
+//! at slots now holding other items.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind};
+use std::ops::{Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+
+use memmap2::{MmapMut, MmapOptions};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use crate::snapshot::{open_sealed, write_sealed, VERSION};
+use crate::{Error, EvictionStrategy, InsertStats, ItemLocation, PrfKind, Table, STASH_SIZE};
+
+const INDEX_MAGIC: &[u8; 4] = b"CKIX";
+/// Size of the trailer after the table data in a data file.
+const TRAILER_SIZE: usize = 8;
+/// Trailer of a data file changed since its last flush.
+const CHANGED: u64 = u64::MAX;
+
+pub enum Storage {
+    Memory(Vec<u8>),
+    /// A shared mapping of a data file, synced by [`Table::flush`].
+    Mapped(MappedFile),
+}
+
+/// A data file mapped in full, trailer included.
+pub struct MappedFile {
+    map: MmapMut,
+    path: PathBuf,
+    /// Generation of the last flush, as recorded in the sidecar.
+    generation: u64,
+    /// Whether the trailer reads `CHANGED`.
+    changed: bool,
+    /// Byte ranges changed since the last flush, as start to end.
+    unflushed: BTreeMap<usize, usize>,
+    /// Failure to sync the `CHANGED` trailer, reported by the next flush.
+    mark_error: Option<std::io::Error>,
+}
+
+impl MappedFile {
+    fn map(file: &File, path: &Path, generation: u64) -> Result<Self, Error> {
+        // SAFETY: the table owns the mapping; the data file must not be modified by other
+        // processes while it is open.
+        let map = unsafe { MmapOptions::new().map_mut(file)? };
+        if map.len() < TRAILER_SIZE {
+            return Err(Error::InvalidSnapshot("data file is truncated".to_string()));
+        }
+        Ok(Self {
+            map,
+            path: path.to_path_buf(),
+            generation,
+            changed: false,
+            unflushed: BTreeMap::new(),
+            mark_error: None,
+        })
+    }
+
+    /// Length of the table data, without the trailer.
+    fn len(&self) -> usize {
+        self.map.len() - TRAILER_SIZE
+    }
+
+    fn trailer(&self) -> u64 {
+        u64::from_le_bytes(self.map[self.len()..].try_into().unwrap())
+    }
+
+    fn set_trailer(&mut self, value: u64) -> std::io::Result<()> {
+        let len = self.len();
+        self.map[len..].copy_from_slice(&value.to_le_bytes());
+        self.map.flush_range(len, TRAILER_SIZE)
+    }
+
+    /// Syncs the `CHANGED` trailer before the first write after a flush, so it reaches the disk
+    /// ahead of any changed data.
+    fn mark_changed(&mut self) {
+        if !self.changed {
+            if let Err(e) = self.set_trailer(CHANGED) {
+                self.mark_error.get_or_insert(e);
+            }
+            self.changed = true;
+        }
+    }
+
+    fn touch(&mut self, range: Range<usize>) {
+        let end = self.unflushed.entry(range.start).or_default();
+        *end = (*end).max(range.end);
+    }
+
+    /// Writes back the changed ranges, merging adjacent ones.
+    fn sync_changes(&mut self) -> Result<(), Error> {
+        if let Some(e) = self.mark_error.take() {
+            return Err(e.into());
+        }
+        let mut pending: Option<Range<usize>> = None;
+        for (&start, &end) in &self.unflushed {
+            match &mut pending {
+                Some(range) if start <= range.end => range.end = range.end.max(end),
+                _ => {
+                    if let Some(range) = pending.replace(start..end) {
+                        self.map.flush_range(range.start, range.len())?;
+                    }
+                }
+            }
+        }
+        if let Some(range) = pending {
+            self.map.flush_range(range.start, range.len())?;
+        }
+        self.unflushed.clear();
+        Ok(())
+    }
+
+    /// Records `generation` in the trailer once the sidecar of that generation is in place.
+    fn commit(&mut self, generation: u64) -> Result<(), Error> {
+        self.set_trailer(generation)?;
+        self.generation = generation;
+        self.changed = false;
+        Ok(())
+    }
+
+    /// Moves the table into a new data file holding `contents`, renamed over the current one.
+    /// On error nothing changes.
+    fn replace(&mut self, contents: &[u8]) -> Result<(), Error> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        match self.write_replacement(&tmp_path, contents) {
+            Ok(replaced) => {
+                *self = replaced;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn write_replacement(&self, tmp_path: &Path, contents: &[u8]) -> Result<Self, Error> {
+        let file = create_data_file(tmp_path, contents.len())?;
+        let mut replaced = Self::map(&file, &self.path, self.generation)?;
+        replaced.mark_changed();
+        replaced.map[..contents.len()].copy_from_slice(contents);
+        replaced.touch(0..contents.len());
+        fs::rename(tmp_path, &self.path)?;
+        Ok(replaced)
+    }
+}
+
+/// Creates (or truncates) a zeroed data file for `len` bytes of table data.
+fn create_data_file(path: &Path, len: usize) -> Result<File, Error> {
+    let file_len = len.checked_add(TRAILER_SIZE).ok_or(Error::InvalidInput)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(file_len as u64)?;
+    Ok(file)
+}
+
+impl Storage {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+
+    /// Records that `range` changed, so the next flush writes it back.
+    pub(crate) fn touch(&mut self, range: Range<usize>) {
+        if let Self::Mapped(file) = self {
+            file.touch(range);
+        }
+    }
+
+    /// Replaces the contents with `contents`. A mapped storage moves to a new data file of the
+    /// new size, which is only paired with a sidecar at the next flush; on error nothing
+    /// changes.
+    pub(crate) fn replace(&mut self, contents: Vec<u8>) -> Result<(), Error> {
+        match self {
+            Self::Memory(data) => *data = contents,
+            Self::Mapped(file) => file.replace(&contents)?,
+        }
+        Ok(())
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::Memory(Vec::new())
+    }
+}
+
+impl Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Memory(data) => data,
+            Self::Mapped(file) => &file.map[..file.len()],
+        }
+    }
+}
+
+impl DerefMut for Storage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Memory(data) => data,
+            Self::Mapped(file) => {
+                file.mark_changed();
+                let len = file.len();
+                &mut file.map[..len]
+            }
+        }
+    }
+}
+
+/// Storages compare by contents, regardless of where they live.
+impl PartialEq for Storage {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<Vec<u8>> for Storage {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<Storage> for Vec<u8> {
+    fn eq(&self, other: &Storage) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl From<Vec<u8>> for Storage {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Memory(data)
+    }
+}
+
+impl std::fmt::Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory(data) => f.debug_tuple("Memory").field(&data.len()).finish(),
+            Self::Mapped(file) => f
+                .debug_struct("Mapped")
+                .field("path", &file.path)
+                .field("len", &file.len())
+                .finish(),
+        }
+    }
+}
+
+impl Table {
+    /// Creates an empty table whose data lives in the file at `path`, replacing any table
+    /// stored there, and writes its sidecar index.
+    pub fn create_mapped<P: AsRef<Path>>(
+        path: P,
+        num_buckets: usize,
+        bucket_depth: usize,
+        item_size: usize,
+        rand_seed: u64,
+        strategy: EvictionStrategy,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let len = num_buckets
+            .checked_mul(bucket_depth)
+            .and_then(|slots| slots.checked_mul(item_size))
+            .ok_or(Error::InvalidInput)?;
+        // A crash before the first flush must not pair the new data file with an old sidecar.
+        match fs::remove_file(index_path(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let file = create_data_file(path, len)?;
+
+        let mut table = Self {
+            num_buckets,
+            bucket_depth,
+            item_size,
+            data: Storage::Mapped(MappedFile::map(&file, path, 0)?),
+            rng: ChaCha12Rng::seed_from_u64(rand_seed),
+            index: vec![ItemLocation::default(); num_buckets * bucket_depth],
+            prf: PrfKind::default(),
+            strategy,
+            stash: Vec::with_capacity(STASH_SIZE),
+            stats: InsertStats::default(),
+            dirty: Default::default(),
+        };
+        table.flush()?;
+        Ok(table)
+    }
+
+    /// Reopens a table created by [`Table::create_mapped`] as of its last [`Table::flush`].
+    ///
+    /// Fails with [`Error::InvalidSnapshot`] if the data file changed after that flush, e.g.
+    /// because the process crashed before flushing again.
+    pub fn open_mapped<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let buf = fs::read(index_path(path))?;
+        let mut r = open_sealed(&buf, INDEX_MAGIC)?;
+        let generation = r.u64()?;
+        let header = r.header()?;
+        let index = r.index(&header)?;
+        let stash = r.stash(&header)?;
+        if !r.buf.is_empty() {
+            return Err(Error::InvalidSnapshot("trailing bytes".to_string()));
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = header
+            .data_size()?
+            .checked_add(TRAILER_SIZE)
+            .ok_or_else(|| Error::InvalidSnapshot("table dimensions overflow".to_string()))?;
+        if file.metadata()?.len() != file_len as u64 {
+            return Err(Error::InvalidSnapshot("data file size does not match index".to_string()));
+        }
+        let data = MappedFile::map(&file, path, generation)?;
+        if data.trailer() != generation {
+            return Err(Error::InvalidSnapshot("data file changed after its last flush".to_string()));
+        }
+        Ok(header.into_table(Storage::Mapped(data), index, stash))
+    }
+
+    /// Makes the current state durable: writes back the buckets changed since the last flush,
+    /// then replaces the sidecar. A no-op for in-memory tables.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let Storage::Mapped(file) = &mut self.data else {
+            return Ok(());
+        };
+        file.sync_changes()?;
+        let changed = file.changed;
+        let generation = file.generation + changed as u64;
+        let index_path = index_path(&file.path);
+
+        let mut buf = Vec::with_capacity(self.index.len() * 32 + 128);
+        buf.extend_from_slice(INDEX_MAGIC);
+        // Sidecar files share the snapshot format version.
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&generation.to_le_bytes());
+        self.put_header(&mut buf);
+        self.put_index(&mut buf);
+        self.put_stash(&mut buf);
+
+        // Write a temporary file and rename it over the sidecar, so a crash leaves either the
+        // old or the new index.
+        let mut tmp_path = index_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(&tmp);
+        write_sealed(&mut writer, &buf)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &index_path)?;
+
+        match &mut self.data {
+            Storage::Mapped(file) if changed => file.commit(generation),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut index_path = path.as_os_str().to_owned();
+    index_path.push(".index");
+    PathBuf::from(index_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{prf, Item};
+
+    const ITEM_SIZE: usize = 16;
+
+    fn item(id: u64, num_buckets: usize) -> Item {
+        let buckets = vec![
+            prf(b"mapped_key_1", id).unwrap() % num_buckets,
+            prf(b"mapped_key_2", id).unwrap() % num_buckets,
+        ];
+        Item::new(id, id.to_le_bytes().repeat(ITEM_SIZE / 8), buckets)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cuckoo-{}-{}", std::process::id(), name))
+    }
+
+    fn remove_files(path: &Path) {
+        fs::remove_file(index_path(path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    /// The byte ranges a flush of `table` would write back.
+    fn unflushed(table: &Table) -> Vec<(usize, usize)> {
+        let Storage::Mapped(file) = &table.data else { panic!("not mapped") };
+        file.unflushed.iter().map(|(&start, &end)| (start, end)).collect()
+    }
+
+    #[test]
+    fn test_mapped_round_trip() {
+        let path = temp_path("round-trip");
+        let mut table = Table::create_mapped(&path, 16, 4, ITEM_SIZE, 1, EvictionStrategy::RandomWalk).unwrap();
+        assert!(table.data.is_mapped());
+        for id in 0..40 {
+            assert!(table.insert(&item(id, 16)).unwrap().is_none());
+        }
+        table.flush().unwrap();
+        let data = table.data.to_vec();
+        drop(table);
+
+        let mut reopened = Table::open_mapped(&path).unwrap();
+        assert_eq!(40, reopened.len());
+        for id in 0..40 {
+            let expected = item(id, 16);
+            assert!(reopened.iter().any(|stored| stored.id == id && stored.data == expected.data));
+        }
+        assert_eq!(data, reopened.data);
+
+        // A flush without changes keeps the pair valid.
+        reopened.flush().unwrap();
+        drop(reopened);
+        let mut reopened = Table::open_mapped(&path).unwrap();
+
+        // Resizing moves to a new data file, paired with the index at the next flush.
+        reopened.resize(32, |item| item.buckets.clone()).unwrap();
+        assert!(reopened.data.is_mapped());
+        assert!(matches!(Table::open_mapped(&path), Err(Error::InvalidSnapshot(_))));
+        reopened.flush().unwrap();
+        assert_eq!((32 * 4 * ITEM_SIZE + TRAILER_SIZE) as u64, fs::metadata(&path).unwrap().len());
+        assert_eq!(40, Table::open_mapped(&path).unwrap().len());
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_flush_writes_back_dirty_buckets() {
+        let path = temp_path("dirty");
+        let mut table = Table::create_mapped(&path, 4, 2, ITEM_SIZE, 1, EvictionStrategy::RandomWalk).unwrap();
+        for id in 0..6 {
+            assert!(table.insert(&item(id, 4)).unwrap().is_none());
+        }
+        table.flush().unwrap();
+        table.take_dirty_buckets();
+        let flushed: Vec<Item> = table.iter().collect();
+
+        // Removals, overwrites and the evictions of a nearly full table move and zero slots the
+        // sidecar still refers to.
+        let first = &flushed[0];
+        table.remove(first.id, &first.buckets).unwrap();
+        table.remove_where(|item| item.id % 2 == 1);
+        let mut changed = item(flushed[1].id, 4);
+        changed.data = vec![0xff; ITEM_SIZE];
+        table.upsert(&changed).unwrap();
+        for id in 100..110 {
+            table.insert(&item(id, 4)).unwrap();
+        }
+
+        // Only the changed buckets are written back.
+        let bucket_size = 2 * ITEM_SIZE;
+        let dirty: Vec<(usize, usize)> = table
+            .take_dirty_buckets()
+            .into_iter()
+            .map(|bucket| (bucket * bucket_size, (bucket + 1) * bucket_size))
+            .collect();
+        assert_eq!(dirty, unflushed(&table));
+
+        // Unflushed changes may already be on disk, so the stale index is refused...
+        let data = table.data.to_vec();
+        assert!(matches!(Table::open_mapped(&path), Err(Error::InvalidSnapshot(_))));
+
+        // ...until a flush commits them.
+        table.flush().unwrap();
+        assert!(unflushed(&table).is_empty());
+        drop(table);
+        let reopened = Table::open_mapped(&path).unwrap();
+        assert_eq!(data, reopened.data);
+        assert!(reopened.iter().all(|stored| stored.id != first.id));
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_open_mapped_rejects_torn_pair() {
+        let path = temp_path("torn");
+        let mut table = Table::create_mapped(&path, 8, 2, ITEM_SIZE, 1, EvictionStrategy::RandomWalk).unwrap();
+        table.insert(&item(1, 8)).unwrap();
+        table.flush().unwrap();
+        let old_index = fs::read(index_path(&path)).unwrap();
+
+        table.insert(&item(2, 8)).unwrap();
+        table.flush().unwrap();
+        drop(table);
+        let new_index = fs::read(index_path(&path)).unwrap();
+
+        // The data of the second flush with the sidecar of the first, as if the rename had
+        // been lost.
+        fs::write(index_path(&path), &old_index).unwrap();
+        assert!(matches!(Table::open_mapped(&path), Err(Error::InvalidSnapshot(_))));
+        fs::write(index_path(&path), &new_index).unwrap();
+        assert_eq!(2, Table::open_mapped(&path).unwrap().len());
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_open_mapped_rejects_mismatched_data() {
+        let path = temp_path("mismatch");
+        Table::create_mapped(&path, 8, 2, ITEM_SIZE, 1, EvictionStrategy::RandomWalk).unwrap();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(10).unwrap();
+        assert!(matches!(Table::open_mapped(&path), Err(Error::InvalidSnapshot(_))));
+
+        remove_files(&path);
+        assert!(matches!(Table::open_mapped(&path), Err(Error::Io(_))));
+    }
+}
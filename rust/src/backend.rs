@@ -0,0 +1,191 @@
+//! PIR backends: how a server database answers queries and how a client builds them.
+//!
+//! `Server` and `Client` are generic over a `PirBackend`. The `dpf` feature provides
+//! `DpfBackend`, the two-server scheme of the C++ libraries. `XorBackend` spreads queries across
+//! any number of servers in pure Rust, and `PlaintextBackend` is an insecure stand-in for tests.
+
+use crate::{
+    client::{Request, Response},
+    error::PirError,
+    records,
+};
+
+pub use crate::xor::XorBackend;
+
+#[cfg(feature = "dpf")]
+pub use crate::dpf::{DpfBackend, DpfClient, ForwardHelperRequest, HelperCipher, LeaderRequest, ServerMode};
+
+/// The backend `Server` and `Client` use unless another is named.
+#[cfg(feature = "dpf")]
+pub type DefaultBackend = DpfBackend;
+#[cfg(not(feature = "dpf"))]
+pub type DefaultBackend = PlaintextBackend;
+
+pub trait PirBackend {
+    /// One server's copy of the database.
+    type Database;
+    /// Client-side query state for a database of fixed size.
+    type Client;
+
+    /// A database serving `rows`, which must all have the same length.
+    fn create_database(rows: Vec<Vec<u8>>) -> Result<Self::Database, PirError>;
+
+    /// Replaces the given rows; nothing changes if any index is out of range.
+    fn update_rows(database: &mut Self::Database, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError>;
+
+    /// Answers one server's share of a request.
+    fn answer(database: &Self::Database, request: &[u8]) -> Result<Vec<u8>, PirError>;
+
+    /// The rows currently served.
+    fn rows(database: &Self::Database) -> &[Vec<u8>];
+
+    fn create_client(database_size: usize) -> Result<Self::Client, PirError>;
+
+    /// One request per server for the rows at `indices`.
+    fn generate_queries(client: &Self::Client, indices: &[usize]) -> Result<Request, PirError>;
+
+    /// Combines the servers' responses into one row per queried index.
+    fn reconstruct(client: &Self::Client, response: Response) -> Result<Vec<Vec<u8>>, PirError>;
+}
+
+/// Sends the queried indices in the clear and returns the rows as they are.
+///
+/// Offers no privacy at all: only use it in tests and local tooling.
+pub struct PlaintextBackend;
+
+impl PirBackend for PlaintextBackend {
+    type Database = Vec<Vec<u8>>;
+    /// The database size.
+    type Client = usize;
+
+    fn create_database(rows: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, PirError> {
+        if rows.is_empty() || rows.iter().any(|row| row.len() != rows[0].len()) {
+            return Err(PirError::InvalidArgument);
+        }
+        Ok(rows)
+    }
+
+    fn update_rows(database: &mut Vec<Vec<u8>>, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError> {
+        if updates.iter().any(|(index, _)| *index >= database.len()) {
+            return Err(PirError::IndexOutOfBounds);
+        }
+        for (index, row) in updates {
+            database[*index] = row.clone();
+        }
+        Ok(())
+    }
+
+    fn answer(database: &Vec<Vec<u8>>, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        let indices = request.chunks_exact(8);
+        if !indices.remainder().is_empty() {
+            return Err(PirError::Processing);
+        }
+        let rows = indices
+            .map(|index| {
+                let index = u64::from_le_bytes(index.try_into().unwrap());
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|index| database.get(index))
+                    .ok_or(PirError::IndexOutOfBounds)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records::encode(&rows))
+    }
+
+    fn rows(database: &Vec<Vec<u8>>) -> &[Vec<u8>] {
+        database
+    }
+
+    fn create_client(database_size: usize) -> Result<usize, PirError> {
+        if database_size == 0 {
+            return Err(PirError::InvalidArgument);
+        }
+        Ok(database_size)
+    }
+
+    fn generate_queries(database_size: &usize, indices: &[usize]) -> Result<Request, PirError> {
+        if indices.iter().any(|index| index >= database_size) {
+            return Err(PirError::IndexOutOfBounds);
+        }
+        let request: Vec<u8> = indices.iter().flat_map(|&index| (index as u64).to_le_bytes()).collect();
+        Ok(Request { shares: vec![request; 2] })
+    }
+
+    /// Every server returns the rows themselves, so their responses must agree.
+    fn reconstruct(_: &usize, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
+        match response.shares.split_first() {
+            Some((first, rest)) if rest.iter().all(|share| share == first) => records::decode(first),
+            _ => Err(PirError::Processing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::Client, server::Server, utils::Key};
+
+    type Database = <PlaintextBackend as PirBackend>::Database;
+
+    fn query(database: &Database, client: &usize, indices: &[usize]) -> Result<Vec<Vec<u8>>, PirError> {
+        let request = PlaintextBackend::generate_queries(client, indices)?;
+        let shares = request
+            .shares
+            .iter()
+            .map(|share| PlaintextBackend::answer(database, share))
+            .collect::<Result<_, _>>()?;
+        let response = Response { shares };
+        PlaintextBackend::reconstruct(client, response)
+    }
+
+    #[test]
+    fn test_plaintext_query_and_update() -> Result<(), PirError> {
+        let rows: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 3]).collect();
+        let mut database = PlaintextBackend::create_database(rows)?;
+        let client = PlaintextBackend::create_client(4)?;
+
+        assert_eq!(query(&database, &client, &[2, 0])?, vec![vec![2; 3], vec![0; 3]]);
+
+        PlaintextBackend::update_rows(&mut database, &[(1, vec![9; 3])])?;
+        assert_eq!(query(&database, &client, &[1])?, vec![vec![9; 3]]);
+
+        assert!(matches!(
+            PlaintextBackend::update_rows(&mut database, &[(0, vec![7; 3]), (4, vec![7; 3])]),
+            Err(PirError::IndexOutOfBounds)
+        ));
+        assert_eq!(PlaintextBackend::rows(&database)[0], vec![0; 3]);
+        assert!(matches!(query(&database, &client, &[4]), Err(PirError::IndexOutOfBounds)));
+
+        let mismatched = Response { shares: vec![records::encode(&[[1u8]]), Vec::new()] };
+        assert!(PlaintextBackend::reconstruct(&client, mismatched).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plaintext_message_round_trip() -> Result<(), PirError> {
+        const TABLE_SIZE: usize = 4;
+        const ITEM_SIZE: usize = 64;
+
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+
+        let mut server1 = Server::<PlaintextBackend>::with_backend(TABLE_SIZE, ITEM_SIZE)?;
+        let mut server2 = Server::<PlaintextBackend>::with_backend(TABLE_SIZE, ITEM_SIZE)?;
+
+        let message = vec![42u8; ITEM_SIZE];
+        let encrypted = alice.encrypt("bob".to_string(), message.clone(), 0)?;
+        let (item, _) = alice.generate_requests("bob".to_string(), encrypted, 0)?;
+        server1.write(item.clone())?;
+        server2.write(item)?;
+
+        let (_, request) = bob.generate_requests("alice".to_string(), Vec::new(), 0)?;
+        let rows = bob.process_responses(Response {
+            shares: vec![server1.get(&request.shares[0])?, server2.get(&request.shares[1])?],
+        })?;
+        assert_eq!(bob.decrypt("alice".to_string(), 0, rows)?, message);
+        Ok(())
+    }
+}
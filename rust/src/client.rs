@@ -1,64 +1,236 @@
-use libc::{c_char, c_int, c_void};
-use std::{ffi::{CStr, CString}, ptr};
 use serde::{Deserialize, Serialize};
-use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
-use cuckoo::{prf as cuckoo_prf, Item};
+use cuckoo::{BucketPrf, CuckooFilter, Item, PrfKind};
 use rand::{thread_rng, Rng};
 
-use crate::{error::{PirError, PirStatus, CryptoError}, utils::{Key, kdf, encrypt, decrypt}, constants::PADDING_SIZE};
+use crate::{backend::{DefaultBackend, PirBackend}, error::{PirError, CryptoError}, identity::{self, Handshake, Identity, PendingHandshake, PublicIdentity}, keystore::{self, KdfParams}, ratchet::Ratchet, types::SizeEpoch, utils::{Key, kdf, encrypt, decrypt}, constants::{BUCKET_DEPTH, NONCE_SIZE, PADDING_SIZE, STASH_ROWS, NUM_CANDIDATE_BUCKETS, FINGERPRINT_SIZE, MAX_SKIPPED_KEYS, POLL_WINDOW}};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use zeroize::Zeroizing;
+
+#[cfg(feature = "dpf")]
+use crate::backend::DpfBackend;
+
+const MESSAGE_CONTEXT: &[u8] = b"talek-message-v1";
+/// Length of the shortest ciphertext `encrypt` produces: nonce, message padded to
+/// `PADDING_SIZE` bytes, and the 16-byte AES-GCM tag.
+const MIN_CIPHERTEXT_SIZE: usize = NONCE_SIZE + PADDING_SIZE + 16;
 
 #[derive(Serialize, Deserialize)]
 pub struct Request {
-    pub request1: String,
-    pub request2: String,
+    /// One share per server, in server order.
+    pub shares: Vec<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Response {
-    pub response1: String,
-    pub response2: String,
+    /// Each server's answer to its share, in the order of the request's shares.
+    pub shares: Vec<Vec<u8>>,
 }
 
-#[link(name = "dpf_client")]
-extern "C" {
-    fn pir_client_create(database_size: c_int, client_handle: *mut *mut c_void) -> PirStatus;
+/// The messages requested by one `Client::batch_read`, needed to split its response.
+pub struct BatchRead {
+    /// Topic, sequence number, and the positions of its rows among the queried indices.
+    reads: Vec<(String, u64, Vec<usize>)>,
+}
 
-    fn pir_client_generate_requests(
-        client_handle: *mut c_void,
-        indices: *const c_int,
-        num_indices: c_int,
-        requests_json: *mut *mut c_char,
-    ) -> PirStatus;
+impl BatchRead {
+    /// The `(to, seq_no)` pairs read, in the order `Client::process_batch` returns them.
+    pub fn reads(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.reads.iter().map(|(to, seq_no, _)| (to.as_str(), *seq_no))
+    }
+}
 
-    fn pir_client_process_responses(
-        responses_json: *const c_char,
-        merged_result: *mut *mut c_char,
-    ) -> PirStatus;
+/// The reads of one `Client::poll`.
+pub struct Poll {
+    from: String,
+    batch: BatchRead,
+}
+
+/// What `Client::process_poll` found.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Polled {
+    /// Messages found, by sequence number in ascending order.
+    pub messages: Vec<(u64, Vec<u8>)>,
+    /// Sequence numbers below the last message found that had no message. Their keys stay
+    /// cached, so they can still be read later with `generate_requests` and `decrypt`.
+    pub missing: Vec<u64>,
+    /// Sequence numbers whose rows held, outside the buckets it belongs in, the peer's message for
+    /// another sequence number of the poll or a skipped one. They are not counted as `missing`,
+    /// and their keys stay cached. This client's own messages served back are not detected and
+    /// show up as `missing`, as do messages moved from other topics or out of the window.
+    pub relocated: Vec<u64>,
+}
+
+/// Keys derived from the shared key of one topic, and the topic's sequence-number cursors.
+#[derive(Clone, Serialize, Deserialize)]
+struct TopicKeys {
+    bucket_keys: Vec<Key>,
+    /// Message keys for what this client sends; only the latest one is kept, for resends.
+    send: Ratchet,
+    /// Message keys for what this client reads, kept for skipped sequence numbers until used.
+    receive: Ratchet,
+    k_fp: Key,
+    /// The sequence number `send` writes next.
+    next_write: u64,
+    /// The first sequence number `poll` reads.
+    next_read: u64,
+}
 
-    fn pir_client_free_string(str: *mut c_char);
-    fn pir_client_destroy(client_handle: *mut c_void);
+/// The state `Client::save` writes, versioned by `KEYSTORE_VERSION`.
+#[derive(Serialize, Deserialize)]
+struct SavedClient {
+    id: String,
+    database_size: i32,
+    size_epochs: Vec<SizeEpoch>,
+    prf: String,
+    identity: Vec<u8>,
+    keys: HashMap<String, TopicKeys>,
 }
 
-pub struct Client {
+pub struct Client<B: PirBackend = DefaultBackend> {
     id: String,
-    handle: *mut c_void,
+    pir: B::Client,
     database_size: i32,
-    keys: HashMap<String, (Key, Key, Key)>,
+    size_epochs: Vec<SizeEpoch>,
+    prf: PrfKind,
+    keys: HashMap<String, TopicKeys>,
+    identity: Identity,
+    /// Handshakes sent and awaiting the peer's reply, by topic.
+    handshakes: HashMap<String, PendingHandshake>,
 }
 
-impl Client {
+#[cfg(feature = "dpf")]
+impl Client<DpfBackend> {
     pub fn new(id: String, database_size: i32) -> Result<Self, PirError> {
+        Self::with_backend(id, database_size)
+    }
+}
+
+impl<B: PirBackend> Client<B> {
+    /// A client querying with the backend `B`, e.g.
+    /// `Client::<PlaintextBackend>::with_backend(id, database_size)`.
+    pub fn with_backend(id: String, database_size: i32) -> Result<Self, PirError> {
         if database_size <= 0 {
             return Err(PirError::InvalidArgument);
         }
 
-        unsafe {
-            let mut handle = ptr::null_mut();
-            let result: Result<(), PirError> = pir_client_create(database_size, &mut handle).into();
-            result.map(|_| Self { id, handle, database_size, keys: HashMap::new() })
+        let pir = B::create_client(database_size as usize + STASH_ROWS)?;
+        let size_epochs = vec![SizeEpoch {
+            epoch: 0,
+            start_seq_no: 0,
+            database_size: database_size as usize,
+        }];
+        Ok(Self {
+            id,
+            pir,
+            database_size,
+            size_epochs,
+            prf: PrfKind::default(),
+            keys: HashMap::new(),
+            identity: Identity::generate(),
+            handshakes: HashMap::new(),
+        })
+    }
+
+    /// Uses an existing identity instead of the freshly generated one.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn public_identity(&self) -> PublicIdentity {
+        self.identity.public()
+    }
+
+    /// Starts agreeing on the key of the topic shared with `to`, whose identity is `peer`. Send
+    /// the returned handshake to the peer, which replies with its own.
+    pub fn start_handshake(&mut self, to: String, peer: PublicIdentity) -> Handshake {
+        let (pending, handshake) = self.identity.start_handshake(peer);
+        self.handshakes.insert(to, pending);
+        handshake
+    }
+
+    /// Completes the handshake started for `to` with the peer's handshake and adds the derived
+    /// topic key, as `add_key` does.
+    pub fn finish_handshake(&mut self, to: String, reply: &Handshake) -> Result<(), PirError> {
+        let pending = self.handshakes.remove(&to).ok_or(PirError::InvalidArgument)?;
+        let key = self.identity.finish_handshake(pending, reply)?;
+        self.add_key(to, key)
+    }
+
+    /// The safety number of this client and `peer`, to compare out of band.
+    pub fn safety_number(&self, peer: &PublicIdentity) -> String {
+        identity::safety_number(&self.public_identity(), peer)
+    }
+
+    /// Selects the PRF used to derive buckets; it must match the one peers use for the topic.
+    pub fn with_prf(mut self, prf: PrfKind) -> Self {
+        self.prf = prf;
+        self
+    }
+
+    /// Writes the client's state to `path`, encrypted under `passphrase`. Handshakes still
+    /// awaiting a reply are not saved and must be started again.
+    pub fn save(&self, path: impl AsRef<Path>, passphrase: &str) -> Result<(), PirError> {
+        self.save_with(path.as_ref(), passphrase, KdfParams::default())
+    }
+
+    fn save_with(&self, path: &Path, passphrase: &str, params: KdfParams) -> Result<(), PirError> {
+        let saved = SavedClient {
+            id: self.id.clone(),
+            database_size: self.database_size,
+            size_epochs: self.size_epochs.clone(),
+            prf: prf_name(self.prf).to_string(),
+            identity: self.identity.to_bytes().to_vec(),
+            keys: self.keys.clone(),
+        };
+        let state = Zeroizing::new(serde_json::to_vec(&saved).map_err(|_| PirError::Processing)?);
+        let file = keystore::seal(passphrase.as_bytes(), &state, params)?;
+
+        // Replace the old file only once the new one is complete and on disk. The file is only
+        // readable by its owner; a stale partial file may have other permissions, so it is
+        // removed rather than reused.
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        match fs::remove_file(&partial) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
         }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut writer = options.open(&partial)?;
+        writer.write_all(&file)?;
+        writer.sync_all()?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Restores a client written by `save`.
+    pub fn load(path: impl AsRef<Path>, passphrase: &str) -> Result<Self, PirError> {
+        let state = keystore::open(passphrase.as_bytes(), &fs::read(path)?)?;
+        let saved: SavedClient = serde_json::from_slice(&state).map_err(|_| PirError::InvalidKeystore)?;
+        let identity = Zeroizing::new(saved.identity);
+        let identity = <&[u8; 64]>::try_from(identity.as_slice()).map_err(|_| PirError::InvalidKeystore)?;
+        let prf = prf_from_name(&saved.prf).ok_or(PirError::InvalidKeystore)?;
+
+        let mut client = Self::with_backend(saved.id, saved.database_size)?
+            .with_identity(Identity::from_bytes(identity))
+            .with_prf(prf);
+        client.set_size_epochs(saved.size_epochs)?;
+        client.keys = saved.keys;
+        Ok(client)
     }
 
     pub fn update_size(&mut self, new_size: i32) -> Result<(), PirError> {
@@ -66,131 +238,658 @@ impl Client {
             return Err(PirError::InvalidArgument);
         }
 
-        let keys = self.keys.clone();
-        let mut new_client = Client::new(self.id.clone(), new_size)?;
-        new_client.keys = keys;
-        
-        unsafe {
-            if !self.handle.is_null() {
-                pir_client_destroy(self.handle);
-            }
-            self.handle = new_client.handle;
-            std::mem::forget(new_client);
+        self.pir = B::create_client(new_size as usize + STASH_ROWS)?;
+        self.database_size = new_size;
+        self.size_epochs = vec![SizeEpoch {
+            epoch: 0,
+            start_seq_no: 0,
+            database_size: new_size as usize,
+        }];
+
+        Ok(())
+    }
+
+    /// Adopts the size epochs published by the server (see `Server::size_epochs`).
+    ///
+    /// PIR queries target the newest database size, while buckets for each sequence number are
+    /// derived with the modulus of the epoch it falls in.
+    pub fn set_size_epochs(&mut self, size_epochs: Vec<SizeEpoch>) -> Result<(), PirError> {
+        let ordered = size_epochs
+            .windows(2)
+            .all(|w| w[0].start_seq_no <= w[1].start_seq_no && w[0].database_size <= w[1].database_size);
+        let latest = size_epochs.last().ok_or(PirError::InvalidArgument)?;
+        if !ordered || latest.database_size == 0 {
+            return Err(PirError::InvalidArgument);
+        }
+
+        let database_size = i32::try_from(latest.database_size).map_err(|_| PirError::InvalidArgument)?;
+        if database_size != self.database_size {
+            self.update_size(database_size)?;
         }
-        
+        self.size_epochs = size_epochs;
         Ok(())
     }
 
+    /// The number of buckets `seq_no` is hashed into.
+    fn bucket_modulus(&self, seq_no: u64) -> usize {
+        self.size_epochs
+            .iter()
+            .rev()
+            .find(|epoch| epoch.start_seq_no <= seq_no)
+            .map_or(self.database_size as usize, |epoch| epoch.database_size)
+    }
+
     pub fn add_key(&mut self, to: String, key: Key) -> Result<(), PirError> {
-        let key1 = kdf(&key, "key1").unwrap();
-        let key2 = kdf(&key, "key2").unwrap();
-        let k_enc = kdf(&key, "k_enc").unwrap();
-        
-        self.keys.insert(to, (key1, key2, k_enc));
+        let bucket_keys = (1..=NUM_CANDIDATE_BUCKETS)
+            .map(|i| kdf(&key, &format!("key{}", i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let send = Ratchet::new(&key, &self.id, 1)?;
+        let receive = Ratchet::new(&key, &to, MAX_SKIPPED_KEYS)?;
+        let k_fp = kdf(&key, "k_fp")?;
+
+        self.keys.insert(to, TopicKeys { bucket_keys, send, receive, k_fp, next_write: 0, next_read: 0 });
 
         Ok(())
     }
 
-    pub fn encrypt(&self, to: String, element: Vec<u8>) -> Result<Vec<u8>, PirError> {
-        let k_enc = self.keys.get(&to).unwrap().2.clone();
-        let encrypted_element = encrypt(&k_enc, &element, PADDING_SIZE).unwrap();
+    /// Encrypts the message for `seq_no` with its own ratchet key, bound to this client as sender,
+    /// `to` as recipient and `seq_no`. Keys of earlier sequence numbers are erased, and a sequence
+    /// number already written is refused; use `reencrypt` to correct the latest message.
+    pub fn encrypt(&mut self, to: String, element: Vec<u8>, seq_no: u64) -> Result<Vec<u8>, PirError> {
+        if seq_no < self.topic(&to)?.next_write {
+            return Err(PirError::SeqNoReused);
+        }
+        self.encrypt_at(to, element, seq_no)
+    }
+
+    /// Encrypts `element` again under the latest sequence number written to `to`, returning that
+    /// sequence number with the ciphertext for `Server::overwrite`. Fails with `SeqNoReused` if
+    /// nothing has been written yet.
+    pub fn reencrypt(&mut self, to: String, element: Vec<u8>) -> Result<(u64, Vec<u8>), PirError> {
+        let seq_no = self.topic(&to)?.next_write.checked_sub(1).ok_or(PirError::SeqNoReused)?;
+        Ok((seq_no, self.encrypt_at(to, element, seq_no)?))
+    }
+
+    fn encrypt_at(&mut self, to: String, element: Vec<u8>, seq_no: u64) -> Result<Vec<u8>, PirError> {
+        let next_write = seq_no.checked_add(1).ok_or(PirError::InvalidArgument)?;
+        let aad = message_aad(&self.id, &to, seq_no);
+        let topic = self.topic_mut(&to)?;
+        let key = topic.send.key(seq_no)?;
+        topic.send.skip_to(next_write)?;
+        topic.next_write = next_write;
+        let encrypted_element = encrypt(&key, &element, PADDING_SIZE, &aad).unwrap();
         Ok(encrypted_element)
     }
 
-    pub fn decrypt(&self, to: String, response: Vec<Vec<u8>>) -> Result<Vec<u8>, PirError> {
-        let k_enc = self.keys.get(&to).unwrap().2.clone();
-        
+    /// Finds and decrypts the message `to` sent this client for `seq_no` in the rows of a
+    /// response, then erases its key. The key is kept if no message is found, so the read can be
+    /// retried.
+    ///
+    /// Messages moved to another sequence number, topic or direction (such as this client's own
+    /// messages served back as the peer's) are under another key and fail with
+    /// `DecryptionFailed`; `process_batch` tells some of these apart. Rows that are not `BUCKET_DEPTH` ciphertexts of one size fail with
+    /// `Processing`.
+    pub fn decrypt(&mut self, to: String, seq_no: u64, response: Vec<Vec<u8>>) -> Result<Vec<u8>, PirError> {
+        let row_len = response.first().map_or(0, Vec::len);
+        let malformed = |row: &Vec<u8>| {
+            row.len() != row_len
+                || !row.len().is_multiple_of(BUCKET_DEPTH)
+                || row.len() / BUCKET_DEPTH < MIN_CIPHERTEXT_SIZE
+        };
+        if response.iter().any(malformed) {
+            return Err(PirError::Processing);
+        }
+
+        let aad = message_aad(&to, &self.id, seq_no);
+        let receive = &mut self.topic_mut(&to)?.receive;
+        let key = receive.key(seq_no)?;
+
         for bucket in response {
-            for chunk in bucket.chunks(bucket.len() / 4) {
-                if let Ok(decrypted_chunk) = decrypt(&k_enc, chunk) {
+            for chunk in bucket.chunks(bucket.len() / BUCKET_DEPTH) {
+                if let Ok(decrypted_chunk) = decrypt(&key, chunk, &aad) {
+                    receive.erase(seq_no)?;
                     return Ok(decrypted_chunk);
                 }
             }
         }
-        
         Err(PirError::Crypto(CryptoError::DecryptionFailed))
     }
 
+    /// Moves the receive ratchet of the topic shared with `to` on to `seq_no`, keeping the keys
+    /// of the sequence numbers passed over (up to `MAX_SKIPPED_KEYS`) for later reads.
+    pub fn skip_to(&mut self, to: String, seq_no: u64) -> Result<(), PirError> {
+        self.topic_mut(&to)?.receive.skip_to(seq_no)?;
+        Ok(())
+    }
+
+
+    /// Encrypts `element` under the next write sequence number of the topic shared with `to` and
+    /// returns that sequence number with the item to `Server::write`.
+    pub fn send(&mut self, to: String, element: Vec<u8>) -> Result<(u64, Item), PirError> {
+        let seq_no = self.topic(&to)?.next_write;
+        let buckets = self.buckets(&to, seq_no)?;
+        let encrypted = self.encrypt(to, element, seq_no)?;
+        Ok((seq_no, Item::new(thread_rng().gen::<u64>(), encrypted, buckets)))
+    }
+
+    /// Requests for the next `POLL_WINDOW` messages from `from`, starting at its read cursor;
+    /// answer them with `Server::get` and pass the response to `process_poll`.
+    pub fn poll(&self, from: String) -> Result<(Poll, Request), PirError> {
+        let next_read = self.topic(&from)?.next_read;
+        let seq_nos: Vec<u64> = (next_read..next_read + POLL_WINDOW as u64).collect();
+        let (batch, request) = self.batch_read(from.clone(), &seq_nos)?;
+        Ok((Poll { from, batch }, request))
+    }
+
+    /// Decrypts the messages of a `poll` and moves the read cursor past the last one found.
+    /// Sequence numbers skipped on the way are reported as `missing`, and those served another
+    /// of the peer's messages as `relocated`. Any other failure is returned.
+    pub fn process_poll(&mut self, poll: Poll, response: Response) -> Result<Polled, PirError> {
+        let results = self.process_batch(&poll.batch, response)?;
+        let mut polled = Polled::default();
+        let mut gap = Vec::new();
+        for ((_, seq_no), result) in poll.batch.reads().zip(results) {
+            match result {
+                Ok(message) => {
+                    polled.missing.append(&mut gap);
+                    polled.messages.push((seq_no, message));
+                }
+                Err(PirError::Crypto(CryptoError::DecryptionFailed)) => gap.push(seq_no),
+                Err(PirError::Crypto(CryptoError::MessageRelocated)) => polled.relocated.push(seq_no),
+                // Already read some other way.
+                Err(PirError::Crypto(CryptoError::KeyErased)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(&(last, _)) = polled.messages.last() {
+            let topic = self.topic_mut(&poll.from)?;
+            topic.next_read = topic.next_read.max(last + 1);
+        }
+        Ok(polled)
+    }
+
+    /// The sequence number `send` uses next for `to`.
+    pub fn next_write_seq_no(&self, to: &str) -> Result<u64, PirError> {
+        Ok(self.topic(to)?.next_write)
+    }
+
+    /// The first sequence number `poll` reads from `from`.
+    pub fn next_read_seq_no(&self, from: &str) -> Result<u64, PirError> {
+        Ok(self.topic(from)?.next_read)
+    }
 
     pub fn generate_requests(&self, to: String, element: Vec<u8>, seq_no: u64) -> Result<(Item, Request), PirError> {
         let mut rng = thread_rng();
         let id = rng.gen::<u64>();
-        let key1 = self.keys.get(&to).unwrap().0.clone();
-        let key2 = self.keys.get(&to).unwrap().1.clone();
-        
-        let bucket1 = cuckoo_prf(key1.as_slice(), seq_no).unwrap() % self.database_size as usize;
-        let bucket2 = cuckoo_prf(key2.as_slice(), seq_no).unwrap() % self.database_size as usize;
-        let item = Item::new(id, element, bucket1, bucket2);
-        
-        self._generate_requests(&[bucket1 as i32, bucket2 as i32]).map(|request| (item, request))
-    }
-
-    pub fn _generate_requests(&self, indices: &[i32]) -> Result<Request, PirError> {
-        unsafe {
-            let mut requests_json = ptr::null_mut();
-            let result: Result<(), PirError> = pir_client_generate_requests(
-                self.handle,
-                indices.as_ptr(),
-                indices.len() as c_int,
-                &mut requests_json,
-            ).into();
-
-            result.and_then(|_| {
-                if requests_json.is_null() {
-                    return Err(PirError::FfiError);
-                }
-                let result = CStr::from_ptr(requests_json)
-                    .to_str()
-                    .map(String::from)
-                    .map_err(|_| PirError::Utf8Error)?;
-                pir_client_free_string(requests_json);
-                serde_json::from_str(&result).map_err(|_| PirError::Processing)
-            })
+        let buckets = self.buckets(&to, seq_no)?;
+        let indices = self.query_indices(&buckets);
+        let item = Item::new(id, element, buckets);
+        self._generate_requests(&indices).map(|request| (item, request))
+    }
+
+    /// Requests for the messages of many sequence numbers of one topic in a single query per
+    /// server; answer them with `Server::get` and pass the response to `process_batch`.
+    pub fn batch_read(&self, to: String, seq_nos: &[u64]) -> Result<(BatchRead, Request), PirError> {
+        let reads: Vec<(String, u64)> = seq_nos.iter().map(|&seq_no| (to.clone(), seq_no)).collect();
+        self.batch_read_from(&reads)
+    }
+
+    /// Like `batch_read`, for messages from several topics.
+    ///
+    /// Buckets shared by several reads and the stash rows are queried once, so servers see the
+    /// number of distinct rows requested but not which reads they belong to.
+    pub fn batch_read_from(&self, reads: &[(String, u64)]) -> Result<(BatchRead, Request), PirError> {
+        if reads.is_empty() {
+            return Err(PirError::InvalidArgument);
+        }
+
+        let mut positions = BTreeMap::new();
+        let mut batch = Vec::with_capacity(reads.len());
+        for (to, seq_no) in reads {
+            let indices = self.query_indices(&self.buckets(to, *seq_no)?);
+            let rows = indices
+                .into_iter()
+                .map(|index| {
+                    let next = positions.len();
+                    *positions.entry(index).or_insert(next)
+                })
+                .collect();
+            batch.push((to.clone(), *seq_no, rows));
+        }
+
+        let mut indices = vec![0; positions.len()];
+        for (index, position) in positions {
+            indices[position] = index;
         }
+        let request = self._generate_requests(&indices)?;
+        Ok((BatchRead { reads: batch }, request))
     }
 
-    pub fn _process_responses(&self, response: Response) -> Result<String, PirError> {
-        unsafe {
-            let responses_json = serde_json::to_string(&response)
-                .map_err(|_| PirError::Processing)?;
-            let c_responses = CString::new(responses_json)
-                .map_err(|_| PirError::InvalidArgument)?;
-            let mut merged_result = ptr::null_mut();
-            let result: Result<(), PirError> = pir_client_process_responses(
-                c_responses.as_ptr(), 
-                &mut merged_result
-            ).into();
-            result.and_then(|_| {
-                if merged_result.is_null() {
-                    return Err(PirError::FfiError);
+    /// Decrypts the message of every read in `batch`, in order. A read fails on its own if no
+    /// message for it is found, with `MessageRelocated` if its rows hold, outside that message's
+    /// own buckets and the stash, the message of another read of the batch or of a sequence number
+    /// whose key is still cached.
+    pub fn process_batch(
+        &mut self,
+        batch: &BatchRead,
+        response: Response,
+    ) -> Result<Vec<Result<Vec<u8>, PirError>>, PirError> {
+        let rows = self.process_responses(response)?;
+        let num_rows = batch.reads.iter().flat_map(|(_, _, positions)| positions).max().map_or(0, |p| p + 1);
+        if rows.len() < num_rows {
+            return Err(PirError::Processing);
+        }
+
+        Ok(batch
+            .reads
+            .iter()
+            .map(|(to, seq_no, positions)| {
+                let rows: Vec<Vec<u8>> = positions.iter().map(|&position| rows[position].clone()).collect();
+                let result = self.decrypt(to.clone(), *seq_no, rows.clone());
+                if matches!(result, Err(PirError::Crypto(CryptoError::DecryptionFailed)))
+                    && self.relocated(to, *seq_no, &rows, batch)?
+                {
+                    return Err(PirError::Crypto(CryptoError::MessageRelocated));
                 }
-                let result = CStr::from_ptr(merged_result)
-                    .to_str()
-                    .map(String::from)
-                    .map_err(|_| PirError::Utf8Error)?;
-                pir_client_free_string(merged_result);
-                Ok(result)
+                result
             })
+            .collect())
+    }
+
+    /// Whether `rows`, read for `seq_no` of `to`, hold the message of another read of `batch` or
+    /// of a cached sequence number of `to` in a row where that message does not belong. Messages
+    /// in their own buckets or the stash are left for their own reads.
+    fn relocated(&self, to: &str, seq_no: u64, rows: &[Vec<u8>], batch: &BatchRead) -> Result<bool, PirError> {
+        let indices = self.query_indices(&self.buckets(to, seq_no)?);
+        let cached = self.topic(to)?.receive.cached_seq_nos().map(|cached| (to, cached));
+        let others = batch.reads().chain(cached).filter(|&other| other != (to, seq_no));
+
+        for (other, other_seq_no) in others {
+            let Ok(key) = self.topic(other)?.receive.key(other_seq_no) else {
+                continue;
+            };
+            let own = self.query_indices(&self.buckets(other, other_seq_no)?);
+            let aad = message_aad(other, &self.id, other_seq_no);
+            for (index, row) in indices.iter().zip(rows) {
+                if own.contains(index) {
+                    continue;
+                }
+                if row.chunks(row.len() / BUCKET_DEPTH).any(|chunk| decrypt(&key, chunk, &aad).is_ok()) {
+                    return Ok(true);
+                }
+            }
         }
+        Ok(false)
     }
 
-    pub fn process_responses(&self, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
-        self._process_responses(response)
-            .map(|result| {
-                result.split(',')
-                    .map(|part| BASE64.decode(part.trim()).unwrap())
-                    .collect()
-            })
+    /// The fingerprint and candidate buckets to publish in the server's membership filter
+    /// alongside the message for `seq_no`.
+    pub fn fingerprint(&self, to: String, seq_no: u64) -> Result<(Vec<u8>, Vec<usize>), PirError> {
+        let k_fp = &self.topic(&to)?.k_fp;
+        let fingerprint = CuckooFilter::fingerprint(k_fp.as_slice(), seq_no, FINGERPRINT_SIZE)
+            .map_err(|_| PirError::Processing)?;
+        Ok((fingerprint, self.buckets(&to, seq_no)?))
+    }
+
+    /// Requests for the filter rows of `seq_no`, to be answered with `Server::get_filter`.
+    pub fn generate_filter_requests(&self, to: String, seq_no: u64) -> Result<Request, PirError> {
+        let buckets = self.buckets(&to, seq_no)?;
+        self._generate_requests(&self.query_indices(&buckets))
+    }
+
+    /// Whether the filter rows returned for `seq_no` (after `process_responses`) announce a
+    /// message. False positives occur with probability about `2^-(8 * FINGERPRINT_SIZE)` per slot.
+    pub fn check_filter(&self, to: String, seq_no: u64, rows: Vec<Vec<u8>>) -> Result<bool, PirError> {
+        let (fingerprint, _) = self.fingerprint(to, seq_no)?;
+        Ok(rows.iter().any(|row| CuckooFilter::row_contains(row, &fingerprint)))
+    }
+
+    fn topic(&self, to: &str) -> Result<&TopicKeys, PirError> {
+        self.keys.get(to).ok_or(PirError::InvalidArgument)
+    }
+
+    fn topic_mut(&mut self, to: &str) -> Result<&mut TopicKeys, PirError> {
+        self.keys.get_mut(to).ok_or(PirError::InvalidArgument)
+    }
+
+    /// Candidate buckets of `seq_no` in the topic shared with `to`.
+    fn buckets(&self, to: &str, seq_no: u64) -> Result<Vec<usize>, PirError> {
+        let modulus = self.bucket_modulus(seq_no);
+        self.topic(to)?
+            .bucket_keys
+            .iter()
+            .map(|key| self.prf.evaluate(key.as_slice(), seq_no).map(|b| b % modulus))
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|_| PirError::Processing)
     }
+
+    /// One PIR query per candidate bucket, plus the stash rows so overflowed items remain
+    /// retrievable.
+    fn query_indices(&self, buckets: &[usize]) -> Vec<usize> {
+        let stash_rows = (0..STASH_ROWS).map(|row| self.database_size as usize + row);
+        buckets.iter().copied().chain(stash_rows).collect()
+    }
+
+    pub fn _generate_requests(&self, indices: &[usize]) -> Result<Request, PirError> {
+        B::generate_queries(&self.pir, indices)
+    }
+
+    /// Combines the two servers' responses into one record per queried index.
+    pub fn process_responses(&self, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
+        B::reconstruct(&self.pir, response)
+    }
+}
+
+/// Associated data binding a message to its sender, recipient and sequence number.
+fn message_aad(sender: &str, recipient: &str, seq_no: u64) -> Vec<u8> {
+    [
+        MESSAGE_CONTEXT,
+        &(sender.len() as u64).to_le_bytes(),
+        sender.as_bytes(),
+        &(recipient.len() as u64).to_le_bytes(),
+        recipient.as_bytes(),
+        &seq_no.to_le_bytes(),
+    ]
+    .concat()
+}
+
+fn prf_name(prf: PrfKind) -> &'static str {
+    match prf {
+        PrfKind::HmacSha256 => "hmac-sha256",
+        PrfKind::Aes128 => "aes128",
+        PrfKind::Blake3 => "blake3",
+    }
+}
+
+fn prf_from_name(name: &str) -> Option<PrfKind> {
+    [PrfKind::HmacSha256, PrfKind::Aes128, PrfKind::Blake3]
+        .into_iter()
+        .find(|&prf| prf_name(prf) == name)
 }
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        unsafe {
-            if !self.handle.is_null() {
-                pir_client_destroy(self.handle);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::PlaintextBackend, server::Server};
+
+    const TABLE_SIZE: usize = 8;
+    const ITEM_SIZE: usize = 64;
+
+    #[test]
+    fn test_batch_read() -> Result<(), PirError> {
+        let mut reader = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        let mut server = Server::<PlaintextBackend>::with_backend(TABLE_SIZE, ITEM_SIZE)?;
+
+        let peers = ["alice", "carol", "dave"];
+        let mut expected = Vec::new();
+        for (i, peer) in peers.iter().enumerate() {
+            let key = Key::new_random();
+            let mut sender = Client::<PlaintextBackend>::with_backend(peer.to_string(), TABLE_SIZE as i32)?;
+            sender.add_key("bob".to_string(), key.clone())?;
+            reader.add_key(peer.to_string(), key)?;
+
+            let message = vec![i as u8 + 1; ITEM_SIZE];
+            let seq_no = 3 * i as u64;
+            let encrypted = sender.encrypt("bob".to_string(), message.clone(), seq_no)?;
+            let (item, _) = sender.generate_requests("bob".to_string(), encrypted, seq_no)?;
+            server.write(item)?;
+            expected.push(((peer.to_string(), seq_no), message));
+        }
+        // A topic without messages fails on its own.
+        reader.add_key("erin".to_string(), Key::new_random())?;
+
+        let mut reads: Vec<(String, u64)> = expected.iter().map(|(read, _)| read.clone()).collect();
+        reads.push(("erin".to_string(), 1));
+        let (batch, request) = reader.batch_read_from(&reads)?;
+        assert!(batch.reads().eq(reads.iter().map(|(to, seq_no)| (to.as_str(), *seq_no))));
+
+        let shares = request.shares.iter().map(|share| server.get(share)).collect::<Result<_, _>>()?;
+        let results = reader.process_batch(&batch, Response { shares })?;
+        assert_eq!(results.len(), reads.len());
+        for ((_, message), result) in expected.iter().zip(&results) {
+            assert_eq!(result.as_ref().ok(), Some(message));
+        }
+        assert!(results[3].is_err());
+
+        // Reads from one topic share the stash rows.
+        let (batch, request) = reader.batch_read("alice".to_string(), &[0, 1, 2])?;
+        let shares = request.shares.iter().map(|share| server.get(share)).collect::<Result<_, _>>()?;
+        let rows = reader.process_responses(Response { shares })?;
+        assert!(rows.len() <= 3 * NUM_CANDIDATE_BUCKETS + STASH_ROWS);
+        assert_eq!(batch.reads().count(), 3);
+
+        assert!(reader.batch_read("alice".to_string(), &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_and_poll() -> Result<(), PirError> {
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+        let mut server = Server::<PlaintextBackend>::with_backend(TABLE_SIZE, ITEM_SIZE)?;
+
+        let poll = |bob: &mut Client<PlaintextBackend>, server: &Server<PlaintextBackend>| {
+            let (poll, request) = bob.poll("alice".to_string())?;
+            let shares = request.shares.iter().map(|share| server.get(share)).collect::<Result<_, _>>()?;
+            bob.process_poll(poll, Response { shares })
+        };
+        assert_eq!(poll(&mut bob, &server)?, Polled::default());
+        assert!(matches!(alice.reencrypt("bob".to_string(), vec![0; ITEM_SIZE]), Err(PirError::SeqNoReused)));
+
+        // The message of seq 1 never reaches the server.
+        let messages: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; ITEM_SIZE]).collect();
+        for (i, message) in messages.iter().enumerate() {
+            let (seq_no, item) = alice.send("bob".to_string(), message.clone())?;
+            assert_eq!(seq_no, i as u64);
+            if seq_no != 1 {
+                server.write(item)?;
+            }
+        }
+        assert_eq!(alice.next_write_seq_no("bob")?, 3);
+        assert!(matches!(alice.encrypt("bob".to_string(), messages[0].clone(), 1), Err(PirError::SeqNoReused)));
+        assert!(matches!(alice.encrypt("bob".to_string(), messages[0].clone(), 2), Err(PirError::SeqNoReused)));
+        assert!(matches!(alice.encrypt("bob".to_string(), messages[0].clone(), u64::MAX), Err(PirError::InvalidArgument)));
+        // Only the latest message can be encrypted again.
+        assert_eq!(alice.reencrypt("bob".to_string(), messages[2].clone())?.0, 2);
+        assert_eq!(alice.next_write_seq_no("bob")?, 3);
+
+        let polled = poll(&mut bob, &server)?;
+        assert_eq!(polled.messages, vec![(0, messages[0].clone()), (2, messages[2].clone())]);
+        assert_eq!(polled.missing, vec![1]);
+        assert!(polled.relocated.is_empty());
+        assert_eq!(bob.next_read_seq_no("alice")?, 3);
+
+        let (seq_no, item) = alice.send("bob".to_string(), messages[1].clone())?;
+        server.write(item)?;
+        assert_eq!(poll(&mut bob, &server)?.messages, vec![(seq_no, messages[1].clone())]);
+        assert_eq!(bob.next_read_seq_no("alice")?, 4);
+
+        // Alice's own messages sit where bob's would; she must not take them for his.
+        let polled = {
+            let (poll, request) = alice.poll("bob".to_string())?;
+            let shares = request.shares.iter().map(|share| server.get(share)).collect::<Result<_, _>>()?;
+            alice.process_poll(poll, Response { shares })?
+        };
+        assert_eq!(polled, Polled::default());
+        assert_eq!(alice.next_read_seq_no("bob")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_reports_relocated_messages() -> Result<(), PirError> {
+        // Large enough that the buckets of nearby sequence numbers are unlikely to all overlap.
+        const LARGE_TABLE_SIZE: usize = 64;
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), LARGE_TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), LARGE_TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+        let mut server = Server::<PlaintextBackend>::with_backend(LARGE_TABLE_SIZE, ITEM_SIZE)?;
+
+        // A later message of the window, written in the buckets of seq 0 that it has no share in.
+        let first = alice.buckets("bob", 0)?;
+        let moved = (1..POLL_WINDOW as u64)
+            .find(|&seq_no| alice.buckets("bob", seq_no).unwrap().iter().all(|bucket| !first.contains(bucket)))
+            .expect("some sequence number of the window has other buckets than seq 0");
+        let encrypted = alice.encrypt("bob".to_string(), vec![3; ITEM_SIZE], moved)?;
+        let (item, _) = alice.generate_requests("bob".to_string(), encrypted, 0)?;
+        server.write(item)?;
+
+        let (poll, request) = bob.poll("alice".to_string())?;
+        let shares = request.shares.iter().map(|share| server.get(share)).collect::<Result<_, _>>()?;
+        let polled = bob.process_poll(poll, Response { shares })?;
+        assert!(polled.messages.is_empty());
+        assert_eq!(polled.relocated.first(), Some(&0));
+        assert!(!polled.relocated.contains(&moved));
+        assert_eq!(bob.next_read_seq_no("alice")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<(), PirError> {
+        const FAST: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?
+            .with_prf(PrfKind::Blake3);
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+
+        let encrypted = alice.encrypt("bob".to_string(), vec![1; ITEM_SIZE], 0)?;
+        assert_eq!(bob.decrypt("alice".to_string(), 0, vec![encrypted.repeat(4)])?, vec![1; ITEM_SIZE]);
+        let (_, buckets) = alice.fingerprint("bob".to_string(), 1)?;
+
+        let path = std::env::temp_dir().join(format!("talek-keystore-{}", thread_rng().gen::<u64>()));
+        alice.save_with(&path, "passphrase", FAST)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, fs::metadata(&path)?.permissions().mode() & 0o777);
+        }
+        assert!(matches!(
+            Client::<PlaintextBackend>::load(&path, "wrong"),
+            Err(PirError::Crypto(CryptoError::DecryptionFailed))
+        ));
+        let mut restored = Client::<PlaintextBackend>::load(&path, "passphrase")?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(restored.id(), "alice");
+        assert_eq!(restored.public_identity(), alice.public_identity());
+        assert_eq!(restored.fingerprint("bob".to_string(), 1)?.1, buckets);
+        // The cursors and ratchets carry on where they were.
+        assert_eq!(restored.next_write_seq_no("bob")?, 1);
+        let encrypted = restored.encrypt("bob".to_string(), vec![2; ITEM_SIZE], 1)?;
+        assert_eq!(bob.decrypt("alice".to_string(), 1, vec![encrypted.repeat(4)])?, vec![2; ITEM_SIZE]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_messages_are_bound_to_sender_and_recipient() -> Result<(), PirError> {
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        let mut carol = Client::<PlaintextBackend>::with_backend("carol".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key.clone())?;
+        carol.add_key("alice".to_string(), key)?;
+
+        let message = vec![5u8; ITEM_SIZE];
+        let rows = vec![alice.encrypt("bob".to_string(), message.clone(), 0)?.repeat(4)];
+        // Served back to its sender as bob's message.
+        assert!(matches!(
+            alice.decrypt("bob".to_string(), 0, rows.clone()),
+            Err(PirError::Crypto(CryptoError::DecryptionFailed))
+        ));
+        // Read by someone holding the topic key but not the recipient.
+        assert!(matches!(
+            carol.decrypt("alice".to_string(), 0, rows.clone()),
+            Err(PirError::Crypto(CryptoError::DecryptionFailed))
+        ));
+        assert_eq!(bob.decrypt("alice".to_string(), 0, rows)?, message);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_rejects_malformed_rows() -> Result<(), PirError> {
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+
+        let row = alice.encrypt("bob".to_string(), vec![5; ITEM_SIZE], 0)?.repeat(BUCKET_DEPTH);
+        let malformed = [
+            vec![vec![]],
+            vec![vec![1; 3]],
+            vec![row[1..].to_vec()],
+            vec![row.clone(), row[..row.len() / 2].to_vec()],
+        ];
+        for rows in malformed {
+            assert!(matches!(bob.decrypt("alice".to_string(), 0, rows), Err(PirError::Processing)));
+        }
+        // The key is kept, so well-formed rows can still be read.
+        assert_eq!(bob.decrypt("alice".to_string(), 0, vec![row])?, vec![5; ITEM_SIZE]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_keys_are_erased() -> Result<(), PirError> {
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<PlaintextBackend>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+
+        let messages: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; ITEM_SIZE]).collect();
+        let encrypted = messages
+            .iter()
+            .enumerate()
+            .map(|(seq_no, message)| alice.encrypt("bob".to_string(), message.clone(), seq_no as u64))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Earlier keys are gone once a later message is sent.
+        assert!(alice.encrypt("bob".to_string(), messages[0].clone(), 0).is_err());
+
+        // Rows hold four items each.
+        let encrypted: Vec<Vec<u8>> = encrypted.iter().map(|element| element.repeat(4)).collect();
+
+        // Out of order, each message decrypts only under its own sequence number, and only once.
+        assert!(bob.decrypt("alice".to_string(), 1, vec![encrypted[2].clone()]).is_err());
+        assert_eq!(bob.decrypt("alice".to_string(), 2, vec![encrypted[2].clone()])?, messages[2]);
+        assert_eq!(bob.decrypt("alice".to_string(), 0, vec![encrypted[0].clone()])?, messages[0]);
+        assert!(matches!(
+            bob.decrypt("alice".to_string(), 2, vec![encrypted[2].clone()]),
+            Err(PirError::Crypto(CryptoError::KeyErased))
+        ));
+        assert_eq!(bob.decrypt("alice".to_string(), 1, vec![encrypted[1].clone()])?, messages[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sent_keys_are_not_in_client_state() -> Result<(), PirError> {
+        let key = Key::new_random();
+        let mut alice = Client::<PlaintextBackend>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key)?;
+
+        let message = vec![7; ITEM_SIZE];
+        let sent = alice.encrypt("bob".to_string(), message.clone(), 0)?;
+        alice.encrypt("bob".to_string(), message, 1)?;
+
+        // Neither chain alice still holds, at any position, opens the message she sent first,
+        // even though her read cursor for bob has not moved.
+        let aad = message_aad("alice", "bob", 0);
+        let topic = alice.topic("bob")?;
+        assert_eq!(topic.receive.next_seq_no(), 0);
+        for ratchet in [&topic.send, &topic.receive] {
+            for seq_no in 0..4 {
+                if let Ok(key) = ratchet.key(seq_no) {
+                    assert!(decrypt(&key, &sent, &aad).is_err());
+                }
             }
         }
+        Ok(())
     }
 }
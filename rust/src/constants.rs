@@ -1,4 +1,27 @@
 pub const BUCKET_DEPTH: usize = 4;  
+/// Number of candidate cuckoo buckets (and bucket KDF subkeys) per message.
+pub const NUM_CANDIDATE_BUCKETS: usize = 2;
 pub const RANDOM_SEED: u64 = 12345; 
 pub const PADDING_SIZE: usize = 16;
-pub const NONCE_SIZE: usize = 12;
\ No newline at end of file
+pub const NONCE_SIZE: usize = 12;
+/// Bytes per fingerprint in the membership filter database.
+pub const FINGERPRINT_SIZE: usize = 4;
+/// PIR rows appended after the buckets to publish the cuckoo stash.
+pub const STASH_ROWS: usize = cuckoo::STASH_SIZE.div_ceil(BUCKET_DEPTH);
+/// Message keys a topic's receive ratchet keeps for sequence numbers it skipped past.
+pub const MAX_SKIPPED_KEYS: usize = 1024;
+/// How far past its position a ratchet may be asked to derive a key.
+pub const MAX_RATCHET_STEPS: u64 = 1 << 16;
+/// Sequence numbers `Client::poll` reads at once, so a missing message does not stall a topic.
+pub const POLL_WINDOW: usize = 4;
+/// Format version written by `Client::save`.
+pub const KEYSTORE_VERSION: u8 = 1;
+/// Argon2id costs for new keystore files: memory in KiB, iterations, and lanes.
+pub const KEYSTORE_M_COST: u32 = 19 * 1024;
+pub const KEYSTORE_T_COST: u32 = 2;
+pub const KEYSTORE_P_COST: u32 = 1;
+/// Highest costs a keystore file may ask for, so a crafted header cannot make `Client::load`
+/// allocate or compute without bound: 1 GiB of memory, 64 iterations and 16 lanes.
+pub const MAX_KEYSTORE_M_COST: u32 = 1 << 20;
+pub const MAX_KEYSTORE_T_COST: u32 = 64;
+pub const MAX_KEYSTORE_P_COST: u32 = 16;
@@ -0,0 +1,543 @@
+//! The two-server DPF backend, backed by the C++ `dpf_server` and `dpf_client` libraries.
+
+use libc::{c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use crate::{
+    backend::PirBackend,
+    client::{Request, Response},
+    error::{PirError, PirStatus},
+    records,
+};
+
+type ForwardHelperRequestFn = unsafe extern "C" fn(
+    context: *mut c_void,
+    request: *const u8,
+    request_len: usize,
+    response: *mut *mut u8,
+    response_len: *mut usize,
+) -> PirStatus;
+
+type HelperCipherFn = unsafe extern "C" fn(
+    context: *mut c_void,
+    input: *const u8,
+    input_len: usize,
+    context_info: *const u8,
+    context_info_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> PirStatus;
+
+/// `pir_server_mode_t`
+#[repr(C)]
+enum ServerModeTag {
+    Plain = 0,
+    Leader = 1,
+    Helper = 2,
+}
+
+/// `pir_server_options_t`
+#[repr(C)]
+struct ServerOptions {
+    mode: ServerModeTag,
+    forward_helper_request: Option<ForwardHelperRequestFn>,
+    decrypt_helper_request: Option<HelperCipherFn>,
+    callback_context: *mut c_void,
+}
+
+#[link(name = "dpf_server")]
+extern "C" {
+    fn pir_server_create(
+        elements: *const u8,
+        elements_len: usize,
+        options: *const ServerOptions,
+        server_handle: *mut *mut c_void,
+    ) -> PirStatus;
+    fn pir_server_update_rows(
+        server_handle: *mut c_void,
+        indices: *const c_int,
+        num_indices: c_int,
+        elements: *const u8,
+        elements_len: usize,
+    ) -> PirStatus;
+    fn pir_server_process_request(
+        server_handle: *mut c_void,
+        request: *const u8,
+        request_len: usize,
+        response: *mut *mut u8,
+        response_len: *mut usize,
+    ) -> PirStatus;
+    fn pir_server_free_buffer(buffer: *mut u8);
+    fn pir_server_destroy(server_handle: *mut c_void);
+}
+
+#[link(name = "dpf_client")]
+extern "C" {
+    fn pir_client_create(database_size: c_int, client_handle: *mut *mut c_void) -> PirStatus;
+
+    fn pir_client_create_leader(
+        database_size: c_int,
+        encrypt_helper_request: HelperCipherFn,
+        context: *mut c_void,
+        client_handle: *mut *mut c_void,
+    ) -> PirStatus;
+
+    fn pir_client_generate_leader_request(
+        client_handle: *mut c_void,
+        indices: *const c_int,
+        num_indices: c_int,
+        request: *mut *mut u8,
+        request_len: *mut usize,
+    ) -> PirStatus;
+
+    fn pir_client_process_leader_response(
+        client_handle: *mut c_void,
+        response: *const u8,
+        response_len: usize,
+        client_state: *const u8,
+        client_state_len: usize,
+        records: *mut *mut u8,
+        records_len: *mut usize,
+    ) -> PirStatus;
+
+    fn pir_client_generate_requests(
+        client_handle: *mut c_void,
+        indices: *const c_int,
+        num_indices: c_int,
+        requests: *mut *mut u8,
+        requests_len: *mut usize,
+    ) -> PirStatus;
+
+    fn pir_client_process_responses(
+        responses: *const u8,
+        responses_len: usize,
+        records: *mut *mut u8,
+        records_len: *mut usize,
+    ) -> PirStatus;
+
+    fn pir_client_free_buffer(buffer: *mut u8);
+    fn pir_client_destroy(client_handle: *mut c_void);
+}
+
+/// Sends a serialized helper request to the helper server and returns its serialized response.
+pub type ForwardHelperRequest = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, PirError>>;
+
+/// Encrypts (on the client) or decrypts (on the helper) the helper's share of a leader request.
+/// The second argument is the context info the ciphertext must be bound to.
+pub type HelperCipher = Box<dyn Fn(&[u8], &[u8]) -> Result<Vec<u8>, PirError>>;
+
+/// How a `PirServer` takes part in a query.
+pub enum ServerMode {
+    /// Answers one of the two plain requests a client sends to both servers.
+    Plain,
+    /// Receives the client's single request and forwards the encrypted helper share.
+    Leader(ForwardHelperRequest),
+    /// Answers helper shares forwarded by the leader, decrypting them first.
+    Helper(HelperCipher),
+}
+
+/// Two-server PIR over distributed point functions. Each server learns nothing about the
+/// queried indices as long as the two do not collude.
+pub struct DpfBackend;
+
+impl PirBackend for DpfBackend {
+    type Database = PirServer;
+    type Client = DpfClient;
+
+    fn create_database(rows: Vec<Vec<u8>>) -> Result<PirServer, PirError> {
+        PirServer::from_rows(rows)
+    }
+
+    fn update_rows(database: &mut PirServer, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError> {
+        database.update_rows(updates)
+    }
+
+    fn answer(database: &PirServer, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        database.process_request(request)
+    }
+
+    fn rows(database: &PirServer) -> &[Vec<u8>] {
+        database.get_elements()
+    }
+
+    fn create_client(database_size: usize) -> Result<DpfClient, PirError> {
+        DpfClient::new(database_size)
+    }
+
+    fn generate_queries(client: &DpfClient, indices: &[usize]) -> Result<Request, PirError> {
+        client.generate_requests(indices)
+    }
+
+    fn reconstruct(client: &DpfClient, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
+        client.process_responses(response)
+    }
+}
+
+pub struct PirServer {
+    handle: *mut c_void,
+    elements: Vec<Vec<u8>>,
+    capacity: usize,
+    /// Boxed so the callback context handed to the C++ server stays put.
+    mode: Box<ServerMode>,
+}
+
+impl PirServer {
+    pub fn new(capacity: usize, item_size: usize) -> Result<Self, PirError> {
+        Self::from_rows(vec![vec![0u8; item_size]; capacity])
+    }
+
+    pub fn from_rows(elements: Vec<Vec<u8>>) -> Result<Self, PirError> {
+        Self::with_mode(elements, ServerMode::Plain)
+    }
+
+    /// A server in a leader/helper pair, or a plain one. Leaders answer the single request of a
+    /// client created with `DpfClient::leader`.
+    pub fn with_mode(elements: Vec<Vec<u8>>, mode: ServerMode) -> Result<Self, PirError> {
+        if elements.is_empty() {
+            return Err(PirError::InvalidArgument);
+        }
+
+        let encoded = records::encode(&elements);
+        let mode = Box::new(mode);
+        let options = ServerOptions {
+            mode: match *mode {
+                ServerMode::Plain => ServerModeTag::Plain,
+                ServerMode::Leader(_) => ServerModeTag::Leader,
+                ServerMode::Helper(_) => ServerModeTag::Helper,
+            },
+            forward_helper_request: Some(forward_helper_request),
+            decrypt_helper_request: Some(decrypt_helper_request),
+            callback_context: &*mode as *const ServerMode as *mut c_void,
+        };
+
+        unsafe {
+            let mut handle = ptr::null_mut();
+            let result: Result<(), PirError> =
+                pir_server_create(encoded.as_ptr(), encoded.len(), &options, &mut handle).into();
+            result.map(|_| Self {
+                handle,
+                capacity: elements.len(),
+                elements,
+                mode,
+            })
+        }
+    }
+
+    pub fn write(&mut self, index: usize, element: Vec<u8>) -> Result<(), PirError> {
+        self.batch_write(&[(index, element)])
+    }
+
+    pub fn batch_write(&mut self, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError> {
+        self.update_rows(updates)
+    }
+
+    /// Replaces the given rows. Only the changed rows cross the FFI boundary, and the C++ server
+    /// updates just those entries of its database.
+    pub fn update_rows(&mut self, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        if updates.iter().any(|(index, _)| *index >= self.capacity) {
+            return Err(PirError::IndexOutOfBounds);
+        }
+
+        let indices: Vec<c_int> = updates.iter().map(|(index, _)| *index as c_int).collect();
+        let rows: Vec<&[u8]> = updates.iter().map(|(_, element)| element.as_slice()).collect();
+        let encoded = records::encode(&rows);
+
+        unsafe {
+            let result: Result<(), PirError> = pir_server_update_rows(
+                self.handle,
+                indices.as_ptr(),
+                indices.len() as c_int,
+                encoded.as_ptr(),
+                encoded.len(),
+            )
+            .into();
+            result?;
+        }
+        for (index, element) in updates {
+            self.elements[*index] = element.clone();
+        }
+        Ok(())
+    }
+
+    /// Answers a serialized PIR request with a serialized response.
+    pub fn process_request(&self, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        unsafe {
+            let mut response_ptr = ptr::null_mut();
+            let mut response_len = 0;
+            let result: Result<(), PirError> = pir_server_process_request(
+                self.handle,
+                request.as_ptr(),
+                request.len(),
+                &mut response_ptr,
+                &mut response_len,
+            )
+            .into();
+            result?;
+            records::take_buffer(response_ptr, response_len, pir_server_free_buffer)
+        }
+    }
+
+    pub fn get_elements(&self) -> &[Vec<u8>] {
+        &self.elements
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn mode(&self) -> &ServerMode {
+        &self.mode
+    }
+}
+
+/// Runs a Rust callback for the C++ side, copying its output into a malloc'd buffer that the
+/// library releases.
+///
+/// # Safety
+///
+/// `output` and `output_len` must be valid for writes.
+unsafe fn run_callback(
+    callback: impl FnOnce() -> Result<Vec<u8>, PirError>,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> PirStatus {
+    match catch_unwind(AssertUnwindSafe(callback)) {
+        Ok(Ok(bytes)) => match records::to_c_buffer(&bytes) {
+            Some(buffer) => {
+                *output = buffer;
+                *output_len = bytes.len();
+                PirStatus::Success
+            }
+            None => PirStatus::ErrorMemory,
+        },
+        _ => PirStatus::ErrorProcessing,
+    }
+}
+
+unsafe extern "C" fn forward_helper_request(
+    context: *mut c_void,
+    request: *const u8,
+    request_len: usize,
+    response: *mut *mut u8,
+    response_len: *mut usize,
+) -> PirStatus {
+    let ServerMode::Leader(forward) = &*(context as *const ServerMode) else {
+        return PirStatus::ErrorInvalidArgument;
+    };
+    let request = slice::from_raw_parts(request, request_len);
+    run_callback(|| forward(request), response, response_len)
+}
+
+unsafe extern "C" fn decrypt_helper_request(
+    context: *mut c_void,
+    ciphertext: *const u8,
+    ciphertext_len: usize,
+    context_info: *const u8,
+    context_info_len: usize,
+    plaintext: *mut *mut u8,
+    plaintext_len: *mut usize,
+) -> PirStatus {
+    let ServerMode::Helper(decrypt) = &*(context as *const ServerMode) else {
+        return PirStatus::ErrorInvalidArgument;
+    };
+    let ciphertext = slice::from_raw_parts(ciphertext, ciphertext_len);
+    let context_info = slice::from_raw_parts(context_info, context_info_len);
+    run_callback(|| decrypt(ciphertext, context_info), plaintext, plaintext_len)
+}
+
+unsafe extern "C" fn encrypt_helper_request(
+    context: *mut c_void,
+    plaintext: *const u8,
+    plaintext_len: usize,
+    context_info: *const u8,
+    context_info_len: usize,
+    ciphertext: *mut *mut u8,
+    ciphertext_len: *mut usize,
+) -> PirStatus {
+    let encrypt = &*(context as *const HelperCipher);
+    let plaintext = slice::from_raw_parts(plaintext, plaintext_len);
+    let context_info = slice::from_raw_parts(context_info, context_info_len);
+    run_callback(|| encrypt(plaintext, context_info), ciphertext, ciphertext_len)
+}
+
+impl Drop for PirServer {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.handle.is_null() {
+                pir_server_destroy(self.handle);
+            }
+        }
+    }
+}
+
+/// A single request for a leader server, with the state needed to read its response.
+pub struct LeaderRequest {
+    pub request: Vec<u8>,
+    pub client_state: Vec<u8>,
+}
+
+/// Query state of the C++ client for a database of fixed size.
+pub struct DpfClient {
+    handle: *mut c_void,
+    /// Set for leader clients; boxed so the callback context handed to C++ stays put.
+    encrypt: Option<Box<HelperCipher>>,
+}
+
+impl DpfClient {
+    pub fn new(database_size: usize) -> Result<Self, PirError> {
+        let database_size = c_database_size(database_size)?;
+
+        unsafe {
+            let mut handle = ptr::null_mut();
+            let result: Result<(), PirError> = pir_client_create(database_size, &mut handle).into();
+            result.map(|_| Self { handle, encrypt: None })
+        }
+    }
+
+    /// A client sending one request to a leader server (see `ServerMode::Leader`), which forwards
+    /// the helper's share encrypted with `encrypt`. Plain requests remain available.
+    pub fn leader(database_size: usize, encrypt: HelperCipher) -> Result<Self, PirError> {
+        let database_size = c_database_size(database_size)?;
+        let encrypt = Box::new(encrypt);
+
+        unsafe {
+            let mut handle = ptr::null_mut();
+            let result: Result<(), PirError> = pir_client_create_leader(
+                database_size,
+                encrypt_helper_request,
+                &*encrypt as *const HelperCipher as *mut c_void,
+                &mut handle,
+            )
+            .into();
+            result.map(|_| Self { handle, encrypt: Some(encrypt) })
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    /// The request for a leader server for the rows at `indices`.
+    pub fn generate_leader_request(&self, indices: &[usize]) -> Result<LeaderRequest, PirError> {
+        if !self.is_leader() {
+            return Err(PirError::InvalidArgument);
+        }
+        let indices = c_indices(indices)?;
+        let buffer = unsafe {
+            let mut request = ptr::null_mut();
+            let mut request_len = 0;
+            let result: Result<(), PirError> = pir_client_generate_leader_request(
+                self.handle,
+                indices.as_ptr(),
+                indices.len() as c_int,
+                &mut request,
+                &mut request_len,
+            ).into();
+            result?;
+            records::take_buffer(request, request_len, pir_client_free_buffer)?
+        };
+
+        match <[Vec<u8>; 2]>::try_from(records::decode(&buffer)?) {
+            Ok([request, client_state]) => Ok(LeaderRequest { request, client_state }),
+            Err(_) => Err(PirError::Processing),
+        }
+    }
+
+    /// Reads the leader's response to `request` into one record per queried index.
+    pub fn process_leader_response(
+        &self,
+        request: &LeaderRequest,
+        response: &[u8],
+    ) -> Result<Vec<Vec<u8>>, PirError> {
+        if !self.is_leader() {
+            return Err(PirError::InvalidArgument);
+        }
+        let buffer = unsafe {
+            let mut records = ptr::null_mut();
+            let mut records_len = 0;
+            let result: Result<(), PirError> = pir_client_process_leader_response(
+                self.handle,
+                response.as_ptr(),
+                response.len(),
+                request.client_state.as_ptr(),
+                request.client_state.len(),
+                &mut records,
+                &mut records_len,
+            ).into();
+            result?;
+            records::take_buffer(records, records_len, pir_client_free_buffer)?
+        };
+        records::decode(&buffer)
+    }
+
+    /// One request per server for the rows at `indices`.
+    pub fn generate_requests(&self, indices: &[usize]) -> Result<Request, PirError> {
+        let indices = c_indices(indices)?;
+        let buffer = unsafe {
+            let mut requests = ptr::null_mut();
+            let mut requests_len = 0;
+            let result: Result<(), PirError> = pir_client_generate_requests(
+                self.handle,
+                indices.as_ptr(),
+                indices.len() as c_int,
+                &mut requests,
+                &mut requests_len,
+            ).into();
+            result?;
+            records::take_buffer(requests, requests_len, pir_client_free_buffer)?
+        };
+
+        let shares = records::decode(&buffer)?;
+        if shares.len() != 2 {
+            return Err(PirError::Processing);
+        }
+        Ok(Request { shares })
+    }
+
+    /// Combines the two servers' responses into one record per queried index. Exactly two shares
+    /// are accepted; `XorBackend` supports more servers.
+    pub fn process_responses(&self, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
+        let responses = records::encode(&response.shares);
+        let buffer = unsafe {
+            let mut merged = ptr::null_mut();
+            let mut merged_len = 0;
+            let result: Result<(), PirError> = pir_client_process_responses(
+                responses.as_ptr(),
+                responses.len(),
+                &mut merged,
+                &mut merged_len,
+            ).into();
+            result?;
+            records::take_buffer(merged, merged_len, pir_client_free_buffer)?
+        };
+        records::decode(&buffer)
+    }
+}
+
+impl Drop for DpfClient {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.handle.is_null() {
+                pir_client_destroy(self.handle);
+            }
+        }
+    }
+}
+
+fn c_database_size(database_size: usize) -> Result<c_int, PirError> {
+    match c_int::try_from(database_size) {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(PirError::InvalidArgument),
+    }
+}
+
+fn c_indices(indices: &[usize]) -> Result<Vec<c_int>, PirError> {
+    indices
+        .iter()
+        .map(|&index| c_int::try_from(index).map_err(|_| PirError::IndexOutOfBounds))
+        .collect()
+}
@@ -25,6 +25,12 @@ pub enum PirError {
     TableFull,
     #[error("Index out of bounds")]
     IndexOutOfBounds,
+    #[error("Sequence number already written")]
+    SeqNoReused,
+    #[error("Unsupported or corrupt keystore file")]
+    InvalidKeystore,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error(transparent)] 
     Crypto(#[from] CryptoError),
 }
@@ -41,6 +47,16 @@ pub enum CryptoError {
     EncryptionFailed,
     #[error("Decryption failed")]
     DecryptionFailed,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Message key no longer available")]
+    KeyErased,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Handshake from an unexpected identity")]
+    UnexpectedIdentity,
+    #[error("Message of another sequence number served outside its buckets")]
+    MessageRelocated,
 }
 
 
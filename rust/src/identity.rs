@@ -0,0 +1,245 @@
+//! Long-term client identities and the handshake deriving a topic key from two of them.
+//!
+//! Each side sends a `Handshake` carrying its public identity and a fresh X25519 key, signed with
+//! its Ed25519 key. The topic key is HKDF over the ephemeral and the static Diffie-Hellman
+//! secrets, bound to both handshakes, so it is fresh per handshake and only the two identity
+//! holders can compute it. Users compare `safety_number`s out of band to rule out a
+//! man-in-the-middle swapping identities.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{thread_rng, RngCore};
+use ring::{digest, hkdf};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    error::CryptoError,
+    utils::{Key, Key16},
+};
+
+const HANDSHAKE_CONTEXT: &[u8] = b"talek-handshake-v1";
+const SAFETY_NUMBER_CONTEXT: &[u8] = b"talek-safety-number-v1";
+
+/// A client's long-term key pairs: X25519 for key agreement, Ed25519 for signatures.
+pub struct Identity {
+    agreement: StaticSecret,
+    signing: SigningKey,
+}
+
+/// The public half of an `Identity`, shared with peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicIdentity {
+    pub agreement: [u8; 32],
+    pub signing: [u8; 32],
+}
+
+/// One side's handshake message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub identity: PublicIdentity,
+    pub ephemeral: [u8; 32],
+    /// Ed25519 signature over the ephemeral key and both identities.
+    pub signature: Vec<u8>,
+}
+
+/// A handshake sent to `peer` and awaiting its reply.
+pub struct PendingHandshake {
+    peer: PublicIdentity,
+    sent: Handshake,
+    ephemeral: EphemeralSecret,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut rng = thread_rng();
+        let mut signing = [0u8; 32];
+        rng.fill_bytes(&mut signing);
+        Self {
+            agreement: StaticSecret::random_from_rng(&mut rng),
+            signing: SigningKey::from_bytes(&signing),
+        }
+    }
+
+    /// The secret keys, agreement then signing, for storing the identity.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 64]> {
+        let mut bytes = Zeroizing::new([0u8; 64]);
+        bytes[..32].copy_from_slice(self.agreement.as_bytes());
+        bytes[32..].copy_from_slice(self.signing.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; 64]) -> Self {
+        let mut agreement = Zeroizing::new([0u8; 32]);
+        agreement.copy_from_slice(&bytes[..32]);
+        let mut signing = Zeroizing::new([0u8; 32]);
+        signing.copy_from_slice(&bytes[32..]);
+        Self {
+            agreement: StaticSecret::from(*agreement),
+            signing: SigningKey::from_bytes(&signing),
+        }
+    }
+
+    pub fn public(&self) -> PublicIdentity {
+        PublicIdentity {
+            agreement: PublicKey::from(&self.agreement).to_bytes(),
+            signing: self.signing.verifying_key().to_bytes(),
+        }
+    }
+
+    /// Starts a handshake with `peer`; send the returned message and keep the pending state for
+    /// `finish_handshake`.
+    pub fn start_handshake(&self, peer: PublicIdentity) -> (PendingHandshake, Handshake) {
+        let ephemeral = EphemeralSecret::random_from_rng(thread_rng());
+        let identity = self.public();
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        let signature = self.signing.sign(&signed_bytes(&identity, &ephemeral_public, &peer));
+        let sent = Handshake {
+            identity,
+            ephemeral: ephemeral_public,
+            signature: signature.to_bytes().to_vec(),
+        };
+        (PendingHandshake { peer, sent: sent.clone(), ephemeral }, sent)
+    }
+
+    /// Checks the peer's reply and derives the topic key both sides share.
+    pub fn finish_handshake(&self, pending: PendingHandshake, reply: &Handshake) -> Result<Key, CryptoError> {
+        if reply.identity != pending.peer {
+            return Err(CryptoError::UnexpectedIdentity);
+        }
+        let verifying_key =
+            VerifyingKey::from_bytes(&reply.identity.signing).map_err(|_| CryptoError::InvalidSignature)?;
+        let signature = Signature::from_slice(&reply.signature).map_err(|_| CryptoError::InvalidSignature)?;
+        verifying_key
+            .verify(&signed_bytes(&reply.identity, &reply.ephemeral, &pending.sent.identity), &signature)
+            .map_err(|_| CryptoError::InvalidSignature)?;
+
+        let ephemeral_secret = pending.ephemeral.diffie_hellman(&PublicKey::from(reply.ephemeral));
+        let static_secret = self.agreement.diffie_hellman(&PublicKey::from(reply.identity.agreement));
+        if !ephemeral_secret.was_contributory() || !static_secret.was_contributory() {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+
+        // Both sides order the two handshakes the same way.
+        let mut transcript = [&pending.sent, reply];
+        transcript.sort_by_key(|handshake| identity_bytes(&handshake.identity));
+        let info: Vec<u8> = transcript
+            .iter()
+            .flat_map(|handshake| [&identity_bytes(&handshake.identity)[..], &handshake.ephemeral].concat())
+            .collect();
+
+        let ikm = [&ephemeral_secret.as_bytes()[..], static_secret.as_bytes()].concat();
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, HANDSHAKE_CONTEXT).extract(&ikm);
+        let info = [info.as_slice()];
+        let okm = prk
+            .expand(&info, Key16)
+            .map_err(|_| CryptoError::HkdfExpansionFailed)?;
+        let mut key = [0u8; 16];
+        okm.fill(&mut key).map_err(|_| CryptoError::HkdfFillFailed)?;
+        Key::from_bytes(&key)
+    }
+}
+
+impl PendingHandshake {
+    pub fn peer(&self) -> &PublicIdentity {
+        &self.peer
+    }
+}
+
+/// A 60-digit number, in groups of five, that two users compare out of band to check they hold
+/// each other's identities. It is the same whichever side computes it.
+pub fn safety_number(a: &PublicIdentity, b: &PublicIdentity) -> String {
+    let mut identities = [identity_bytes(a), identity_bytes(b)];
+    identities.sort();
+    identities
+        .iter()
+        .flat_map(|identity| {
+            let hash = digest::digest(&digest::SHA512, &[SAFETY_NUMBER_CONTEXT, identity].concat());
+            hash.as_ref()[..30]
+                .chunks(5)
+                .map(|chunk| {
+                    let value = chunk.iter().fold(0u64, |acc, &byte| acc << 8 | byte as u64);
+                    format!("{:05}", value % 100_000)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn identity_bytes(identity: &PublicIdentity) -> [u8; 64] {
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&identity.agreement);
+    bytes[32..].copy_from_slice(&identity.signing);
+    bytes
+}
+
+fn signed_bytes(identity: &PublicIdentity, ephemeral: &[u8; 32], peer: &PublicIdentity) -> Vec<u8> {
+    [HANDSHAKE_CONTEXT, &identity_bytes(identity), ephemeral, &identity_bytes(peer)].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_agrees_on_key() -> Result<(), CryptoError> {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+
+        let (alice_pending, alice_hello) = alice.start_handshake(bob.public());
+        let (bob_pending, bob_hello) = bob.start_handshake(alice.public());
+        let alice_key = alice.finish_handshake(alice_pending, &bob_hello)?;
+        let bob_key = bob.finish_handshake(bob_pending, &alice_hello)?;
+        assert_eq!(alice_key, bob_key);
+
+        // A new handshake yields a new key.
+        let (alice_pending, _) = alice.start_handshake(bob.public());
+        let (_, bob_hello) = bob.start_handshake(alice.public());
+        assert_ne!(alice.finish_handshake(alice_pending, &bob_hello)?, alice_key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_rejects_forgeries() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mallory = Identity::generate();
+
+        // A handshake from someone other than the expected peer
+        let (pending, _) = alice.start_handshake(bob.public());
+        let (_, forged) = mallory.start_handshake(alice.public());
+        assert!(matches!(alice.finish_handshake(pending, &forged), Err(CryptoError::UnexpectedIdentity)));
+
+        // Bob's identity with a swapped ephemeral key
+        let (pending, _) = alice.start_handshake(bob.public());
+        let (_, mut tampered) = bob.start_handshake(alice.public());
+        tampered.ephemeral = forged.ephemeral;
+        assert!(matches!(alice.finish_handshake(pending, &tampered), Err(CryptoError::InvalidSignature)));
+
+        // Bob's handshake meant for Mallory
+        let (pending, _) = alice.start_handshake(bob.public());
+        let (_, redirected) = bob.start_handshake(mallory.public());
+        assert!(matches!(alice.finish_handshake(pending, &redirected), Err(CryptoError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_identity_bytes_round_trip() {
+        let alice = Identity::generate();
+        let restored = Identity::from_bytes(&alice.to_bytes());
+        assert_eq!(restored.public(), alice.public());
+    }
+
+    #[test]
+    fn test_safety_number() {
+        let alice = Identity::generate().public();
+        let bob = Identity::generate().public();
+        let carol = Identity::generate().public();
+
+        let number = safety_number(&alice, &bob);
+        assert_eq!(number, safety_number(&bob, &alice));
+        assert_ne!(number, safety_number(&alice, &carol));
+        assert_eq!(number.split(' ').count(), 12);
+        assert!(number.split(' ').all(|group| group.len() == 5 && group.bytes().all(|b| b.is_ascii_digit())));
+    }
+}
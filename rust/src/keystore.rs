@@ -0,0 +1,122 @@
+//! Passphrase-encrypted files for saving client state.
+//!
+//! A file is `MAGIC || version || m_cost || t_cost || p_cost || salt || ciphertext`, the costs as
+//! little-endian `u32`s. The key is Argon2id over the passphrase and salt with the stored costs, so
+//! the costs can be raised later without breaking older files; the ciphertext is
+//! `utils::encrypt` of the state under that key, with the header as associated data.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{thread_rng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::{
+    constants::{
+        KEYSTORE_M_COST, KEYSTORE_P_COST, KEYSTORE_T_COST, KEYSTORE_VERSION, MAX_KEYSTORE_M_COST,
+        MAX_KEYSTORE_P_COST, MAX_KEYSTORE_T_COST,
+    },
+    error::PirError,
+    utils::{self, Key},
+};
+
+const MAGIC: &[u8] = b"talek-keystore";
+const SALT_SIZE: usize = 16;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 3 * 4 + SALT_SIZE;
+
+/// Argon2id memory (KiB), iteration and parallelism costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self { m_cost: KEYSTORE_M_COST, t_cost: KEYSTORE_T_COST, p_cost: KEYSTORE_P_COST }
+    }
+}
+
+/// Encrypts `plaintext` under a key derived from `passphrase` with a fresh salt.
+pub(crate) fn seal(passphrase: &[u8], plaintext: &[u8], params: KdfParams) -> Result<Vec<u8>, PirError> {
+    let mut salt = [0u8; SALT_SIZE];
+    thread_rng().fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt, params)?;
+
+    let mut file = Vec::with_capacity(HEADER_SIZE + plaintext.len());
+    file.extend_from_slice(MAGIC);
+    file.push(KEYSTORE_VERSION);
+    for cost in [params.m_cost, params.t_cost, params.p_cost] {
+        file.extend_from_slice(&cost.to_le_bytes());
+    }
+    file.extend_from_slice(&salt);
+    let ciphertext = utils::encrypt(&key, plaintext, 0, &file)?;
+    file.extend_from_slice(&ciphertext);
+    Ok(file)
+}
+
+/// Decrypts a file written by `seal`. A wrong passphrase fails with `DecryptionFailed`; costs
+/// above the `MAX_KEYSTORE_*` bounds fail with `InvalidKeystore` before any key is derived.
+pub(crate) fn open(passphrase: &[u8], file: &[u8]) -> Result<Zeroizing<Vec<u8>>, PirError> {
+    if file.len() < HEADER_SIZE || !file.starts_with(MAGIC) {
+        return Err(PirError::InvalidKeystore);
+    }
+    let (header, ciphertext) = file.split_at(HEADER_SIZE);
+    let (&version, header) = header[MAGIC.len()..].split_first().ok_or(PirError::InvalidKeystore)?;
+    if version != KEYSTORE_VERSION {
+        return Err(PirError::InvalidKeystore);
+    }
+
+    let (costs, salt) = header.split_at(3 * 4);
+    let cost = |i: usize| u32::from_le_bytes(costs[4 * i..4 * i + 4].try_into().unwrap());
+    let params = KdfParams { m_cost: cost(0), t_cost: cost(1), p_cost: cost(2) };
+    let key = derive_key(passphrase, salt, params)?;
+    Ok(Zeroizing::new(utils::decrypt(&key, ciphertext, &file[..HEADER_SIZE])?))
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: KdfParams) -> Result<Key, PirError> {
+    if params.m_cost > MAX_KEYSTORE_M_COST
+        || params.t_cost > MAX_KEYSTORE_T_COST
+        || params.p_cost > MAX_KEYSTORE_P_COST
+    {
+        return Err(PirError::InvalidKeystore);
+    }
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(16))
+        .map_err(|_| PirError::InvalidKeystore)?;
+    let mut key = Zeroizing::new([0u8; 16]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, key.as_mut())
+        .map_err(|_| PirError::InvalidKeystore)?;
+    Ok(Key::from_bytes(key.as_ref())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CryptoError;
+
+    const FAST: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+    #[test]
+    fn test_seal_and_open() -> Result<(), PirError> {
+        let file = seal(b"correct horse", b"state", FAST)?;
+        assert_eq!(open(b"correct horse", &file)?.as_slice(), b"state");
+        assert!(matches!(open(b"wrong horse", &file), Err(PirError::Crypto(CryptoError::DecryptionFailed))));
+
+        // Same passphrase, fresh salt.
+        assert_ne!(seal(b"correct horse", b"state", FAST)?[..HEADER_SIZE], file[..HEADER_SIZE]);
+
+        let mut future = file.clone();
+        future[MAGIC.len()] = KEYSTORE_VERSION + 1;
+        assert!(matches!(open(b"correct horse", &future), Err(PirError::InvalidKeystore)));
+        assert!(matches!(open(b"correct horse", &file[..HEADER_SIZE - 1]), Err(PirError::InvalidKeystore)));
+
+        // Costs beyond the bounds are refused rather than computed.
+        let costs = MAGIC.len() + 1;
+        for (i, max) in [MAX_KEYSTORE_M_COST, MAX_KEYSTORE_T_COST, MAX_KEYSTORE_P_COST].into_iter().enumerate() {
+            let mut costly = file.clone();
+            costly[costs + 4 * i..costs + 4 * i + 4].copy_from_slice(&(max + 1).to_le_bytes());
+            assert!(matches!(open(b"correct horse", &costly), Err(PirError::InvalidKeystore)));
+        }
+        Ok(())
+    }
+}
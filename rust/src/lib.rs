@@ -1,8 +1,16 @@
 mod error;
 pub mod types;
 mod constants;
+mod records;
 pub mod utils;
+pub mod identity;
+pub mod ratchet;
+mod keystore;
+pub mod backend;
+#[cfg(feature = "dpf")]
+mod dpf;
+mod xor;
 pub mod client;
 pub mod server;
 
-pub use error::PirError;
\ No newline at end of file
+pub use error::{CryptoError, PirError};
\ No newline at end of file
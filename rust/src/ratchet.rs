@@ -0,0 +1,146 @@
+//! Symmetric hash ratchet giving every message of a topic its own encryption key.
+//!
+//! Chain key `c_n` yields the message key of sequence number `n` and the next chain key
+//! `c_{n+1}`, both through `kdf`, so earlier keys cannot be recomputed from later state. Message
+//! keys of sequence numbers the chain has moved past are kept in a bounded cache until used.
+//!
+//! Each direction of a topic has its own chain, labelled with the sender's id, so the chain a
+//! client reads with cannot derive the keys of the messages it sent.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::MAX_RATCHET_STEPS,
+    error::CryptoError,
+    utils::{kdf, Key},
+};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Ratchet {
+    /// Chain key for `next_seq_no`.
+    chain_key: Key,
+    next_seq_no: u64,
+    /// Message keys of sequence numbers below `next_seq_no` not erased yet.
+    keys: BTreeMap<u64, Key>,
+    max_keys: usize,
+}
+
+impl Ratchet {
+    /// The chain of the messages `sender` writes to the topic, starting at sequence number 0
+    /// and keeping at most `max_keys` passed-over keys.
+    pub fn new(topic_key: &Key, sender: &str, max_keys: usize) -> Result<Self, CryptoError> {
+        Ok(Self {
+            chain_key: kdf(topic_key, &format!("chain:{}", sender))?,
+            next_seq_no: 0,
+            keys: BTreeMap::new(),
+            max_keys,
+        })
+    }
+
+    pub fn next_seq_no(&self) -> u64 {
+        self.next_seq_no
+    }
+
+    /// The message key of `seq_no`, leaving the chain unchanged. Fails if the key was erased or
+    /// `seq_no` is more than `MAX_RATCHET_STEPS` ahead.
+    pub fn key(&self, seq_no: u64) -> Result<Key, CryptoError> {
+        if seq_no < self.next_seq_no {
+            return self.keys.get(&seq_no).cloned().ok_or(CryptoError::KeyErased);
+        }
+        if seq_no - self.next_seq_no > MAX_RATCHET_STEPS {
+            return Err(CryptoError::KeyErased);
+        }
+
+        let mut chain_key = self.chain_key.clone();
+        for _ in self.next_seq_no..seq_no {
+            chain_key = kdf(&chain_key, "chain")?;
+        }
+        kdf(&chain_key, "message")
+    }
+
+    /// Sequence numbers below `next_seq_no` whose keys are still cached, in ascending order.
+    pub fn cached_seq_nos(&self) -> impl Iterator<Item = u64> + '_ {
+        self.keys.keys().copied()
+    }
+
+    /// Moves the chain on to `seq_no`, keeping the keys passed over, except the oldest ones beyond
+    /// the cache bound. Does nothing if the chain is already there.
+    pub fn skip_to(&mut self, seq_no: u64) -> Result<(), CryptoError> {
+        if seq_no <= self.next_seq_no {
+            return Ok(());
+        }
+        if seq_no - self.next_seq_no > MAX_RATCHET_STEPS {
+            return Err(CryptoError::KeyErased);
+        }
+
+        // Only the newest `max_keys` keys survive, so older ones need not be stored at all.
+        let keep_from = seq_no.saturating_sub(self.max_keys as u64);
+        while self.next_seq_no < seq_no {
+            if self.next_seq_no >= keep_from {
+                self.keys.insert(self.next_seq_no, kdf(&self.chain_key, "message")?);
+            }
+            self.chain_key = kdf(&self.chain_key, "chain")?;
+            self.next_seq_no += 1;
+        }
+        while self.keys.len() > self.max_keys {
+            self.keys.pop_first();
+        }
+        Ok(())
+    }
+
+    /// Forgets the message key of `seq_no` for good.
+    pub fn erase(&mut self, seq_no: u64) -> Result<(), CryptoError> {
+        self.skip_to(seq_no + 1)?;
+        self.keys.remove(&seq_no);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_match_across_chains() -> Result<(), CryptoError> {
+        let topic_key = Key::new_random();
+        let sender = Ratchet::new(&topic_key, "alice", 4)?;
+        let mut receiver = Ratchet::new(&topic_key, "alice", 4)?;
+
+        let keys = (0..6).map(|seq_no| sender.key(seq_no)).collect::<Result<Vec<_>, _>>()?;
+        assert!(keys.windows(2).all(|pair| pair[0] != pair[1]));
+
+        // Out of order: 3 first, then 1 from the cache.
+        assert_eq!(receiver.key(3)?, keys[3]);
+        receiver.erase(3)?;
+        assert_eq!(receiver.next_seq_no(), 4);
+        assert_eq!(receiver.key(1)?, keys[1]);
+        receiver.erase(1)?;
+        assert!(matches!(receiver.key(1), Err(CryptoError::KeyErased)));
+        assert!(matches!(receiver.key(3), Err(CryptoError::KeyErased)));
+        assert_eq!(receiver.key(5)?, keys[5]);
+
+        // The other direction of the topic shares no keys with this one.
+        let reply = Ratchet::new(&topic_key, "bob", 4)?;
+        assert!((0..6).all(|seq_no| keys.iter().all(|key| reply.key(seq_no).unwrap() != *key)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_keys_are_bounded() -> Result<(), CryptoError> {
+        let topic_key = Key::new_random();
+        let sender = Ratchet::new(&topic_key, "alice", 2)?;
+        let mut receiver = Ratchet::new(&topic_key, "alice", 2)?;
+
+        receiver.skip_to(10)?;
+        assert!(matches!(receiver.key(7), Err(CryptoError::KeyErased)));
+        assert_eq!(receiver.key(8)?, sender.key(8)?);
+        assert_eq!(receiver.key(9)?, sender.key(9)?);
+        assert_eq!(receiver.key(10)?, sender.key(10)?);
+
+        assert!(receiver.key(11 + MAX_RATCHET_STEPS).is_err());
+        assert!(receiver.skip_to(11 + MAX_RATCHET_STEPS).is_err());
+        Ok(())
+    }
+}
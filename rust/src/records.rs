@@ -0,0 +1,66 @@
+//! Length-prefixed record arrays, the format of every byte buffer exchanged with the C++
+//! libraries (see `c/records.h`): each record is a little-endian `u32` length followed by that
+//! many bytes, back to back.
+
+use crate::error::PirError;
+
+pub(crate) fn encode<T: AsRef<[u8]>>(records: &[T]) -> Vec<u8> {
+    let total: usize = records.iter().map(|record| 4 + record.as_ref().len()).sum();
+    let mut buf = Vec::with_capacity(total);
+    for record in records {
+        let record = record.as_ref();
+        buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buf.extend_from_slice(record);
+    }
+    buf
+}
+
+pub(crate) fn decode(mut buf: &[u8]) -> Result<Vec<Vec<u8>>, PirError> {
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            return Err(PirError::Processing);
+        }
+        let (len, rest) = buf.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(PirError::Processing);
+        }
+        let (record, rest) = rest.split_at(len);
+        records.push(record.to_vec());
+        buf = rest;
+    }
+    Ok(records)
+}
+
+/// Copies a buffer returned by the C++ side and releases it with `free`.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to `len` readable bytes allocated by the library owning `free`.
+#[cfg(feature = "dpf")]
+pub(crate) unsafe fn take_buffer(
+    ptr: *mut u8,
+    len: usize,
+    free: unsafe extern "C" fn(*mut u8),
+) -> Result<Vec<u8>, PirError> {
+    if ptr.is_null() {
+        return Err(PirError::FfiError);
+    }
+    let bytes = std::slice::from_raw_parts(ptr, len).to_vec();
+    free(ptr);
+    Ok(bytes)
+}
+
+/// Copies `bytes` into a malloc'd buffer for the C++ side to release with `free`.
+#[cfg(feature = "dpf")]
+pub(crate) fn to_c_buffer(bytes: &[u8]) -> Option<*mut u8> {
+    unsafe {
+        let buffer = libc::malloc(bytes.len().max(1)) as *mut u8;
+        if buffer.is_null() {
+            return None;
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer, bytes.len());
+        Some(buffer)
+    }
+}
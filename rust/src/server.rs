@@ -1,203 +1,339 @@
-use cuckoo::{prf, Item, Table};
-use libc::{c_char, c_int, c_void};
+use cuckoo::{prf, CuckooFilter, EvictionStrategy, InsertStats, Item, Table};
 use rand::{thread_rng, Rng, RngCore};
-use std::ffi::{CStr, CString};
-use std::ptr;
-use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use std::collections::{HashMap, VecDeque};
 
 use crate::{
-    constants::{BUCKET_DEPTH, RANDOM_SEED, PADDING_SIZE, NONCE_SIZE},
-    error::{PirError, PirStatus},
+    backend::{DefaultBackend, PirBackend},
+    types::SizeEpoch,
+    constants::{BUCKET_DEPTH, RANDOM_SEED, PADDING_SIZE, NONCE_SIZE, STASH_ROWS, FINGERPRINT_SIZE},
+    error::PirError,
 };
 
-#[link(name = "dpf_server")]
-extern "C" {
-    fn pir_server_create(
-        elements: *const *const c_char,
-        num_elements: c_int,
-        server_handle: *mut *mut c_void,
-    ) -> PirStatus;
-    fn pir_server_process_request(
-        server_handle: *mut c_void,
-        request_base64: *const c_char,
-        response_base64: *mut *mut c_char,
-    ) -> PirStatus;
-    fn pir_server_free_string(str: *mut c_char);
-    fn pir_server_destroy(server_handle: *mut c_void);
-}
+#[cfg(feature = "dpf")]
+pub use crate::dpf::PirServer;
+#[cfg(feature = "dpf")]
+use crate::backend::DpfBackend;
 
-pub struct PirServer {
-    handle: *mut c_void,
-    elements: Vec<String>,
-    capacity: usize,
+pub struct Server<B: PirBackend = DefaultBackend> {
+    pir: B::Database,
+    table: Table,
+    item_size: usize,
+    size_epochs: Vec<SizeEpoch>,
+    /// Membership filter over the same buckets, served as a second, much smaller PIR database
+    /// so clients can check for a message before fetching it.
+    filter: CuckooFilter,
+    filter_pir: B::Database,
+    /// Retention epoch, advanced by `advance_epoch`; unrelated to size epochs.
+    epoch: u64,
+    /// Epoch in which each stored message was last written, by item id.
+    item_epochs: HashMap<u64, u64>,
+    /// Published fingerprints with the epoch they were written in, oldest first.
+    fingerprint_epochs: VecDeque<(u64, Vec<u8>, Vec<usize>)>,
 }
 
-
-impl PirServer {
+#[cfg(feature = "dpf")]
+impl Server<DpfBackend> {
     pub fn new(capacity: usize, item_size: usize) -> Result<Self, PirError> {
+        Self::with_backend(capacity, item_size)
+    }
+}
+
+impl<B: PirBackend> Server<B> {
+    /// A server answering queries with the backend `B`, e.g.
+    /// `Server::<PlaintextBackend>::with_backend(capacity, item_size)`.
+    pub fn with_backend(capacity: usize, item_size: usize) -> Result<Self, PirError> {
         if capacity == 0 {
             return Err(PirError::InvalidArgument);
         }
+        let table = Table::new(
+            capacity,
+            BUCKET_DEPTH,
+            item_size + PADDING_SIZE + NONCE_SIZE,
+            Some(vec![0u8; capacity * BUCKET_DEPTH * (item_size + PADDING_SIZE + NONCE_SIZE)]),
+            RANDOM_SEED,
+            EvictionStrategy::RandomWalk,
+        )
+        .ok_or(PirError::InvalidArgument)?;
+        let pir = zeroed_database::<B>(capacity + STASH_ROWS, BUCKET_DEPTH * (item_size + PADDING_SIZE + NONCE_SIZE))?;
+        let size_epochs = vec![SizeEpoch {
+            epoch: 0,
+            start_seq_no: 0,
+            database_size: capacity,
+        }];
+        let filter = CuckooFilter::new(capacity, BUCKET_DEPTH, FINGERPRINT_SIZE, RANDOM_SEED)
+            .ok_or(PirError::InvalidArgument)?;
+        let filter_pir = zeroed_database::<B>(capacity + STASH_ROWS, BUCKET_DEPTH * FINGERPRINT_SIZE)?;
 
-        let elements: Vec<String> = (0..capacity).map(|_| {
-            BASE64.encode(vec![0u8; item_size])
-        }).collect();
-        
-        unsafe {
-            let c_strings: Vec<CString> = elements
-                .iter()
-                .map(|element| {
-                    CString::new(element.clone())
-                        .map_err(|_| PirError::InvalidArgument)
-                })
-                .collect::<Result<Vec<_>, _>>()?;
-
-            let c_ptrs: Vec<*const c_char> = c_strings.iter().map(|cs| cs.as_ptr()).collect();
-            let mut handle = ptr::null_mut();
-            let result: Result<(), PirError> =
-                pir_server_create(c_ptrs.as_ptr(), capacity as c_int, &mut handle).into();
-            result.map(|_| Self {
-                handle,
-                elements,
-                capacity,
-            })
-        }
+        Ok(Self {
+            pir,
+            table,
+            item_size,
+            size_epochs,
+            filter,
+            filter_pir,
+            epoch: 0,
+            item_epochs: HashMap::new(),
+            fingerprint_epochs: VecDeque::new(),
+        })
     }
 
-    pub fn write(&mut self, index: usize, element: String) -> Result<(), PirError> {
-        self.batch_write(&[(index, element)])
+    pub fn write(&mut self, item: Item) -> Result<(), PirError> {
+        self.batch_write(&[item])
     }
-    
-    pub fn batch_write(&mut self, updates: &[(usize, String)]) -> Result<(), PirError> {
-        for (index, _) in updates {
-            if *index >= self.capacity {
-                return Err(PirError::IndexOutOfBounds);
+
+    /// Writes every item in `updates`. On `TableFull` the items written before the failure stay
+    /// stored and are published like the others; the failed item and those after it are not
+    /// written, and no stored message is displaced to make room.
+    pub fn batch_write(&mut self, updates: &[Item]) -> Result<(), PirError> {
+        let mut written = Ok(());
+        for item in updates {
+            match self.table.insert(item) {
+                Ok(None) => {
+                    self.item_epochs.insert(item.id, self.epoch);
+                }
+                Ok(Some(_)) | Err(_) => {
+                    written = Err(PirError::TableFull);
+                    break;
+                }
             }
         }
-        let mut updated_elements = self.elements.clone();
-        for (index, element) in updates {
-            updated_elements[*index] = element.clone();
-        }
-        unsafe {
-            let c_strings: Vec<CString> = updated_elements
-                .iter()
-                .map(|element| {
-                    CString::new(element.clone())
-                        .map_err(|_| PirError::InvalidArgument)
-                })
-                .collect::<Result<Vec<_>, _>>()?;
-
-            let c_ptrs: Vec<*const c_char> = c_strings.iter().map(|cs| cs.as_ptr()).collect();
-            let mut new_handle = ptr::null_mut();
-            let result: Result<(), PirError> =
-                pir_server_create(c_ptrs.as_ptr(), self.capacity as c_int, &mut new_handle).into();
-            result?;
-            if !self.handle.is_null() {
-                pir_server_destroy(self.handle);
+
+        self.update_pir_data()?;
+        written
+    }
+
+    /// Replaces the content of an already published message with the same id (for example a
+    /// corrected resend) in place, or writes it as a new message if the id is unknown.
+    pub fn overwrite(&mut self, item: Item) -> Result<(), PirError> {
+        match self.table.upsert(&item) {
+            Ok(None) => {
+                self.item_epochs.insert(item.id, self.epoch);
+                self.update_pir_data()
             }
-            self.handle = new_handle;
-            self.elements = updated_elements;
+            Ok(Some(_)) | Err(_) => Err(PirError::TableFull),
         }
-        Ok(())
     }
 
-    pub fn process_request(&self, request_base64: &str) -> Result<String, PirError> {
-        unsafe {
-            let c_request = CString::new(request_base64).map_err(|_| PirError::InvalidArgument)?;
-
-            let mut response_ptr = ptr::null_mut();
-            let result: Result<(), PirError> =
-                pir_server_process_request(self.handle, c_request.as_ptr(), &mut response_ptr)
-                    .into();
-            result.and_then(|_| {
-                if response_ptr.is_null() {
-                    return Err(PirError::FfiError);
-                }
-                let result = CStr::from_ptr(response_ptr)
-                    .to_str()
-                    .map(String::from)
-                    .map_err(|_| PirError::Utf8Error)?;
-                pir_server_free_string(response_ptr);
-                Ok(result)
-            })
+    /// Grows the table to `new_capacity` buckets, starting a new size epoch at `start_seq_no`.
+    ///
+    /// Stored items keep their buckets, which remain valid because the table only grows; clients
+    /// use the returned epoch to pick the modulus for sequence numbers from `start_seq_no` on.
+    pub fn resize(&mut self, new_capacity: usize, start_seq_no: u64) -> Result<SizeEpoch, PirError> {
+        let current = *self.size_epochs.last().unwrap();
+        if new_capacity < self.table.num_buckets || start_seq_no < current.start_seq_no {
+            return Err(PirError::InvalidArgument);
         }
+
+        // Build everything at the new size first, so a failure leaves the server unchanged.
+        let mut table = self
+            .table
+            .resized(new_capacity, |item| item.buckets.clone())
+            .map_err(|_| PirError::TableFull)?;
+        let mut filter = self.filter.grown(new_capacity).map_err(|_| PirError::TableFull)?;
+        let mut pir = zeroed_database::<B>(
+            new_capacity + STASH_ROWS,
+            BUCKET_DEPTH * (self.item_size + PADDING_SIZE + NONCE_SIZE),
+        )?;
+        B::update_rows(&mut pir, &table_rows(&mut table))?;
+        let mut filter_pir = zeroed_database::<B>(new_capacity + STASH_ROWS, BUCKET_DEPTH * FINGERPRINT_SIZE)?;
+        B::update_rows(&mut filter_pir, &filter_rows(&mut filter))?;
+
+        self.table = table;
+        self.filter = filter;
+        self.pir = pir;
+        self.filter_pir = filter_pir;
+
+        let epoch = SizeEpoch {
+            epoch: current.epoch + 1,
+            start_seq_no,
+            database_size: new_capacity,
+        };
+        self.size_epochs.push(epoch);
+        Ok(epoch)
     }
 
-    pub fn get_elements(&self) -> &[String] {
-        &self.elements
+    /// Every size epoch so far, oldest first; clients need these to derive buckets.
+    pub fn size_epochs(&self) -> &[SizeEpoch] {
+        &self.size_epochs
     }
 
-    pub fn capacity(&self) -> usize {
-        self.capacity
+    pub fn get(&self, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        B::answer(&self.pir, request)
     }
-}
 
-impl Drop for PirServer {
-    fn drop(&mut self) {
-        unsafe {
-            if !self.handle.is_null() {
-                pir_server_destroy(self.handle);
+    /// Publishes the fingerprint of a message (see `Client::fingerprint`) in the filter database.
+    pub fn write_fingerprint(&mut self, fingerprint: &[u8], buckets: Vec<usize>) -> Result<(), PirError> {
+        match self.filter.insert(fingerprint, buckets.clone()) {
+            Ok(true) => {
+                self.fingerprint_epochs.push_back((self.epoch, fingerprint.to_vec(), buckets));
+                self.update_filter_pir_data()
             }
+            Ok(false) => Err(PirError::TableFull),
+            Err(_) => Err(PirError::InvalidArgument),
         }
     }
-}
 
-pub struct Server {
-    pir: PirServer,
-    table: Table,
-}
+    /// Starts the next retention epoch, dropping every message and fingerprint written more than
+    /// `retain` epochs ago; `retain == 0` drops everything. Both PIR databases are rebuilt once.
+    /// Returns the number of messages removed.
+    pub fn advance_epoch(&mut self, retain: usize) -> Result<usize, PirError> {
+        self.epoch += 1;
+        let expired = |written: u64| self.epoch - written > retain as u64;
 
-impl Server {
-    pub fn new(capacity: usize, item_size: usize) -> Result<Self, PirError> {
-        if capacity == 0 {
-            return Err(PirError::InvalidArgument);
+        let removed = self.table.remove_where(|item| {
+            self.item_epochs.get(&item.id).is_some_and(|&written| expired(written))
+        });
+        for item in &removed {
+            self.item_epochs.remove(&item.id);
+        }
+
+        let mut filter_changed = false;
+        while self.fingerprint_epochs.front().is_some_and(|(written, ..)| expired(*written)) {
+            let (_, fingerprint, buckets) = self.fingerprint_epochs.pop_front().unwrap();
+            self.filter.remove(&fingerprint, &buckets);
+            filter_changed = true;
         }
-        let table = Table::new(
-            capacity,
-            BUCKET_DEPTH,
-            item_size + PADDING_SIZE + NONCE_SIZE,
-            Some(vec![0u8; capacity * BUCKET_DEPTH * (item_size + PADDING_SIZE + NONCE_SIZE)]),
-            RANDOM_SEED,
-        )
-        .ok_or(PirError::InvalidArgument)?;
-        let pir = PirServer::new(capacity, item_size + PADDING_SIZE + NONCE_SIZE)?;
 
-        Ok(Self { pir, table })
+        if !removed.is_empty() {
+            self.update_pir_data()?;
+        }
+        if filter_changed {
+            self.update_filter_pir_data()?;
+        }
+        Ok(removed.len())
     }
 
-    pub fn write(&mut self, item: Item) -> Result<(), PirError> {
-        self.batch_write(&[item])
+    /// The current retention epoch.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
     }
 
-    pub fn batch_write(&mut self, updates: &[Item]) -> Result<(), PirError> {
-        for item in updates {
-            if self.table.insert(&item).is_err() {
-                return Err(PirError::TableFull);
-            }
-        }
+    /// Answers a request made with `Client::generate_filter_requests`.
+    pub fn get_filter(&self, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        B::answer(&self.filter_pir, request)
+    }
 
-        self.update_pir_data()
+    /// Republishes the buckets the table reports as changed, plus the stash rows. A resized
+    /// table reports every bucket, matching the freshly created PIR database.
+    fn update_pir_data(&mut self) -> Result<(), PirError> {
+        B::update_rows(&mut self.pir, &table_rows(&mut self.table))
     }
 
-    pub fn get(&self, request_base64: &String) -> Result<String, PirError> {
-        self.pir.process_request(request_base64)
+    fn update_filter_pir_data(&mut self) -> Result<(), PirError> {
+        B::update_rows(&mut self.filter_pir, &filter_rows(&mut self.filter))
     }
 
-    fn update_pir_data(&mut self) -> Result<(), PirError> {
-        let bucket_size = BUCKET_DEPTH * self.table.item_size;
-        let updates: Vec<(usize, String)> = (0..self.table.num_buckets)
-            .map(|bucket_idx| {
-                let start = bucket_idx * bucket_size;
-                let data = &self.table.data[start..start + bucket_size];
-                let encoded = BASE64.encode(data);
-                (bucket_idx, encoded)
-            })
-            .collect();
+    /// Items the cuckoo table could not place, which are served from the stash rows.
+    pub fn stash(&self) -> &[Item] {
+        self.table.stash()
+    }
+
+    pub fn get_elements(&self) -> &[Vec<u8>] {
+        B::rows(&self.pir)
+    }
+
+    /// Number of messages currently stored, including stashed ones.
+    pub fn num_items(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Fraction of cuckoo slots in use; alert on this before writes start failing with
+    /// `PirError::TableFull`.
+    pub fn load_factor(&self) -> f64 {
+        self.table.load_factor()
+    }
 
-        self.pir.batch_write(&updates)
+    /// Entry `k` is the number of buckets holding exactly `k` messages.
+    pub fn bucket_fill_histogram(&self) -> Vec<usize> {
+        self.table.bucket_fill_histogram()
     }
 
-    pub fn get_elements(&self) -> &[String] {
-        self.pir.get_elements()
+    pub fn insert_stats(&self) -> &InsertStats {
+        self.table.insert_stats()
     }
-}
\ No newline at end of file
+}
+
+/// A database of `num_rows` all-zero rows of `row_size` bytes.
+fn zeroed_database<B: PirBackend>(num_rows: usize, row_size: usize) -> Result<B::Database, PirError> {
+    B::create_database(vec![vec![0u8; row_size]; num_rows])
+}
+
+/// The rows of the buckets `table` reports as changed, plus the stash rows.
+fn table_rows(table: &mut Table) -> Vec<(usize, Vec<u8>)> {
+    let bucket_size = BUCKET_DEPTH * table.item_size;
+    let buckets = table.take_dirty_buckets();
+    pir_rows(&table.data, table.stash_data(), bucket_size, &buckets)
+}
+
+fn filter_rows(filter: &mut CuckooFilter) -> Vec<(usize, Vec<u8>)> {
+    let buckets = filter.take_dirty_buckets();
+    pir_rows(filter.to_bytes(), filter.stash_bytes(), filter.bucket_size(), &buckets)
+}
+
+/// The PIR rows of `buckets` in `data`, followed by the `STASH_ROWS` rows holding the
+/// stash padded to full rows.
+fn pir_rows(data: &[u8], mut stash: Vec<u8>, bucket_size: usize, buckets: &[usize]) -> Vec<(usize, Vec<u8>)> {
+    let num_buckets = data.len() / bucket_size;
+    stash.resize(STASH_ROWS * bucket_size, 0);
+    buckets
+        .iter()
+        .map(|&bucket| (bucket, &data[bucket * bucket_size..(bucket + 1) * bucket_size]))
+        .chain(stash.chunks(bucket_size).enumerate().map(|(row, chunk)| (num_buckets + row, chunk)))
+        .map(|(row, chunk)| (row, chunk.to_vec()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::PlaintextBackend;
+
+    const ITEM_SIZE: usize = 8;
+
+    fn item(id: u64, buckets: Vec<usize>) -> Item {
+        Item::new(id, vec![id as u8; ITEM_SIZE + PADDING_SIZE + NONCE_SIZE], buckets)
+    }
+
+    /// Whether the PIR rows hold `item`'s data.
+    fn published(server: &Server<PlaintextBackend>, item: &Item) -> bool {
+        server
+            .get_elements()
+            .iter()
+            .any(|row| row.chunks(item.data.len()).any(|slot| slot == item.data.as_slice()))
+    }
+
+    #[test]
+    fn test_failed_batch_write_publishes_written_items() -> Result<(), PirError> {
+        let mut server = Server::<PlaintextBackend>::with_backend(1, ITEM_SIZE)?;
+        let capacity = BUCKET_DEPTH + cuckoo::STASH_SIZE;
+        let items: Vec<Item> = (1..=capacity as u64 + 1).map(|id| item(id, vec![0])).collect();
+
+        assert!(matches!(server.batch_write(&items), Err(PirError::TableFull)));
+        assert_eq!(server.num_items(), capacity);
+        // The item refused is the last one; every earlier message is still stored.
+        assert!(server.table.iter().all(|stored| stored.id <= capacity as u64));
+        assert_eq!(server.table.iter().filter(|stored| published(&server, stored)).count(), server.num_items());
+        Ok(())
+    }
+
+    #[test]
+    fn test_advance_epoch_retains_recent_epochs() -> Result<(), PirError> {
+        let mut server = Server::<PlaintextBackend>::with_backend(4, ITEM_SIZE)?;
+        server.write(item(1, vec![0, 1]))?;
+        server.write_fingerprint(&[1; FINGERPRINT_SIZE], vec![0, 1])?;
+
+        // Written in the epoch that just ended, so one retained epoch keeps it.
+        assert_eq!(0, server.advance_epoch(1)?);
+        assert_eq!(1, server.num_items());
+        assert_eq!(1, server.filter.len());
+        server.write(item(2, vec![2, 3]))?;
+
+        assert_eq!(1, server.advance_epoch(1)?);
+        assert!(server.table.iter().all(|stored| stored.id == 2));
+        assert!(server.filter.is_empty());
+
+        assert_eq!(1, server.advance_epoch(0)?);
+        assert_eq!(0, server.num_items());
+        Ok(())
+    }
+}
@@ -2,12 +2,23 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
-    pub request1: String,
-    pub request2: String,
+    /// One share per server, in server order.
+    pub shares: Vec<Vec<u8>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
-    pub response1: String,
-    pub response2: String,
-}
\ No newline at end of file
+    /// Each server's answer to its share, in the order of the request's shares.
+    pub shares: Vec<Vec<u8>>,
+}
+
+/// A period during which clients derive buckets modulo a fixed database size.
+///
+/// Growing the server table starts a new epoch: sequence numbers from `start_seq_no` onwards
+/// map into `database_size` buckets, while earlier ones keep the modulus of their own epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeEpoch {
+    pub epoch: u64,
+    pub start_seq_no: u64,
+    pub database_size: usize,
+}
@@ -3,9 +3,11 @@ use ring::{digest, hkdf, hmac};
 use aes_gcm::aead::{AeadInPlace, KeyInit};
 use aes_gcm::{Aes128Gcm, Nonce};
 use rand::{Rng, thread_rng};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 use crate::{error::CryptoError, constants::NONCE_SIZE};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Key(Vec<u8>);
 
 impl Key {
@@ -16,6 +18,14 @@ impl Key {
         Key(key.to_vec())
     }
 
+    /// A key from its 16 raw bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CryptoError> {
+        if bytes.len() != 16 {
+            return Err(CryptoError::InvalidKeyLength);
+        }
+        Ok(Key(bytes.to_vec()))
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         self.0.clone()
     }
@@ -25,8 +35,14 @@ impl Key {
     }
 }
 
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 /// A custom KeyType that tells Ring's HKDF to produce a 16-byte output.
-struct Key16;
+pub(crate) struct Key16;
 
 impl hkdf::KeyType for Key16 {
     fn len(&self) -> usize {
@@ -87,17 +103,18 @@ fn pad_message(message: &[u8], target_length: usize) -> Vec<u8> {
 /// * `key` - The encryption key
 /// * `message` - The message to encrypt
 /// * `padding_size` - The padding size for the message
+/// * `aad` - Associated data authenticated along with the message but not included in the output
 ///
 /// # Returns
 /// The encrypted message as a byte vector, or an error if encryption fails.
-pub fn encrypt(key: &Key, message: &[u8], padding_size: usize) -> Result<Vec<u8>, CryptoError> {
+pub fn encrypt(key: &Key, message: &[u8], padding_size: usize, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let cipher = Aes128Gcm::new_from_slice(key.as_slice())
         .map_err(|_| CryptoError::EncryptionFailed)?;
     let nonce_bytes = rand::thread_rng().gen::<[u8; NONCE_SIZE]>();
     let nonce = Nonce::from_slice(&nonce_bytes);
     let mut buffer = pad_message(message, padding_size);
     cipher
-        .encrypt_in_place(nonce, b"", &mut buffer)
+        .encrypt_in_place(nonce, aad, &mut buffer)
         .map_err(|_| CryptoError::EncryptionFailed)?;
     Ok([nonce.as_slice(), buffer.as_slice()].concat())
 }
@@ -107,10 +124,12 @@ pub fn encrypt(key: &Key, message: &[u8], padding_size: usize) -> Result<Vec<u8>
 /// # Arguments
 /// * `key` - The encryption key
 /// * `ciphertext` - The encrypted message
+/// * `aad` - The associated data the message was encrypted with
 ///
 /// # Returns
-/// The decrypted message as a byte vector, or an error if decryption fails.
-pub fn decrypt(key: &Key, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+/// The decrypted message as a byte vector, or an error if decryption fails, including when
+/// `aad` differs from the one used to encrypt.
+pub fn decrypt(key: &Key, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     if ciphertext.len() < 12 {
         return Err(CryptoError::DecryptionFailed);
     }
@@ -120,7 +139,7 @@ pub fn decrypt(key: &Key, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let nonce = Nonce::from_slice(nonce);
     let mut buffer = Vec::from(ciphertext);
     cipher
-        .decrypt_in_place(nonce, b"", &mut buffer)
+        .decrypt_in_place(nonce, aad, &mut buffer)
         .map_err(|_| CryptoError::DecryptionFailed)?;
     Ok(buffer)
 }
\ No newline at end of file
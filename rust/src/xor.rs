@@ -0,0 +1,214 @@
+//! N-server XOR PIR in pure Rust.
+//!
+//! To query row `i` the client sends each of the first `N - 1` servers a random seed, and the
+//! last server the unit vector `e_i` XORed with the bit vectors those seeds expand to. Every
+//! server answers with the XOR of the rows its vector selects, and XORing all `N` answers yields
+//! row `i`. Any `N - 1` servers together only see uniformly random vectors; the last server's
+//! request grows with the database, one bit per row.
+
+use rand::{thread_rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::{
+    backend::{PirBackend, PlaintextBackend},
+    client::{Request, Response},
+    error::PirError,
+    records,
+};
+
+const SEED_SIZE: usize = 32;
+const SEED_QUERY: u8 = 0;
+const VECTOR_QUERY: u8 = 1;
+
+/// XOR PIR across `N >= 2` servers, private unless all `N` collude.
+pub struct XorBackend<const N: usize>;
+
+impl<const N: usize> PirBackend for XorBackend<N> {
+    type Database = Vec<Vec<u8>>;
+    /// The database size.
+    type Client = usize;
+
+    fn create_database(rows: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, PirError> {
+        PlaintextBackend::create_database(rows)
+    }
+
+    fn update_rows(database: &mut Vec<Vec<u8>>, updates: &[(usize, Vec<u8>)]) -> Result<(), PirError> {
+        PlaintextBackend::update_rows(database, updates)
+    }
+
+    fn answer(database: &Vec<Vec<u8>>, request: &[u8]) -> Result<Vec<u8>, PirError> {
+        let answers = records::decode(request)?
+            .iter()
+            .map(|query| {
+                let selection = match query.split_first() {
+                    Some((&SEED_QUERY, seed)) => {
+                        let seed = <[u8; SEED_SIZE]>::try_from(seed).map_err(|_| PirError::Processing)?;
+                        expand(seed, database.len())
+                    }
+                    Some((&VECTOR_QUERY, vector)) if vector.len() == database.len().div_ceil(8) => {
+                        vector.to_vec()
+                    }
+                    _ => return Err(PirError::Processing),
+                };
+                let mut answer = vec![0u8; database[0].len()];
+                for (index, row) in database.iter().enumerate() {
+                    if selection[index / 8] >> (index % 8) & 1 == 1 {
+                        xor_into(&mut answer, row);
+                    }
+                }
+                Ok(answer)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records::encode(&answers))
+    }
+
+    fn rows(database: &Vec<Vec<u8>>) -> &[Vec<u8>] {
+        database
+    }
+
+    fn create_client(database_size: usize) -> Result<usize, PirError> {
+        if N < 2 {
+            return Err(PirError::InvalidArgument);
+        }
+        PlaintextBackend::create_client(database_size)
+    }
+
+    fn generate_queries(database_size: &usize, indices: &[usize]) -> Result<Request, PirError> {
+        if indices.iter().any(|index| index >= database_size) {
+            return Err(PirError::IndexOutOfBounds);
+        }
+
+        let mut rng = thread_rng();
+        let mut shares = vec![Vec::with_capacity(indices.len()); N];
+        for &index in indices {
+            let mut vector = vec![0u8; database_size.div_ceil(8)];
+            vector[index / 8] |= 1 << (index % 8);
+            for share in &mut shares[..N - 1] {
+                let mut seed = [0u8; SEED_SIZE];
+                rng.fill_bytes(&mut seed);
+                xor_into(&mut vector, &expand(seed, *database_size));
+                share.push([&[SEED_QUERY][..], &seed].concat());
+            }
+            shares[N - 1].push([&[VECTOR_QUERY][..], &vector].concat());
+        }
+
+        Ok(Request { shares: shares.iter().map(|queries| records::encode(queries)).collect() })
+    }
+
+    fn reconstruct(_: &usize, response: Response) -> Result<Vec<Vec<u8>>, PirError> {
+        if response.shares.len() != N {
+            return Err(PirError::Processing);
+        }
+        let mut answers = response.shares.iter().map(|share| records::decode(share));
+        let mut rows = answers.next().unwrap()?;
+        for answer in answers {
+            let answer = answer?;
+            if answer.len() != rows.len() {
+                return Err(PirError::Processing);
+            }
+            for (row, share) in rows.iter_mut().zip(&answer) {
+                if row.len() != share.len() {
+                    return Err(PirError::Processing);
+                }
+                xor_into(row, share);
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// The selection bit vector of `num_rows` rows a seed stands for.
+fn expand(seed: [u8; SEED_SIZE], num_rows: usize) -> Vec<u8> {
+    let mut vector = vec![0u8; num_rows.div_ceil(8)];
+    ChaCha20Rng::from_seed(seed).fill_bytes(&mut vector);
+    vector
+}
+
+fn xor_into(target: &mut [u8], bytes: &[u8]) {
+    for (t, b) in target.iter_mut().zip(bytes) {
+        *t ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::Client, server::Server, utils::Key};
+
+    fn query<const N: usize>(database: &[Vec<u8>], indices: &[usize]) -> Result<Vec<Vec<u8>>, PirError> {
+        let database = database.to_vec();
+        let client = XorBackend::<N>::create_client(database.len())?;
+        let request = XorBackend::<N>::generate_queries(&client, indices)?;
+        assert_eq!(request.shares.len(), N);
+        let shares = request
+            .shares
+            .iter()
+            .map(|share| XorBackend::<N>::answer(&database, share))
+            .collect::<Result<_, _>>()?;
+        XorBackend::<N>::reconstruct(&client, Response { shares })
+    }
+
+    #[test]
+    fn test_xor_query() -> Result<(), PirError> {
+        let rows: Vec<Vec<u8>> = (0..11u8).map(|i| vec![i, i.wrapping_mul(7), 0xff]).collect();
+
+        assert_eq!(query::<2>(&rows, &[3])?, vec![rows[3].clone()]);
+        assert_eq!(query::<3>(&rows, &[10, 0, 10])?, vec![rows[10].clone(), rows[0].clone(), rows[10].clone()]);
+        assert_eq!(query::<5>(&rows, &[7])?, vec![rows[7].clone()]);
+
+        assert!(matches!(query::<3>(&rows, &[11]), Err(PirError::IndexOutOfBounds)));
+        assert!(XorBackend::<1>::create_client(rows.len()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_xor_rejects_missing_shares() -> Result<(), PirError> {
+        let database = XorBackend::<3>::create_database(vec![vec![1u8; 4]; 4])?;
+        let client = XorBackend::<3>::create_client(4)?;
+        let request = XorBackend::<3>::generate_queries(&client, &[1])?;
+
+        // Seed shares alone reveal nothing about the row.
+        assert!(request.shares[..2].iter().all(|share| share.len() == 4 + 1 + SEED_SIZE));
+
+        let shares = request.shares[..2]
+            .iter()
+            .map(|share| XorBackend::<3>::answer(&database, share))
+            .collect::<Result<_, _>>()?;
+        assert!(XorBackend::<3>::reconstruct(&client, Response { shares }).is_err());
+        assert!(XorBackend::<3>::answer(&database, &records::encode(&[[VECTOR_QUERY]])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_three_server_message_round_trip() -> Result<(), PirError> {
+        const TABLE_SIZE: usize = 4;
+        const ITEM_SIZE: usize = 64;
+
+        let key = Key::new_random();
+        let mut alice = Client::<XorBackend<3>>::with_backend("alice".to_string(), TABLE_SIZE as i32)?;
+        let mut bob = Client::<XorBackend<3>>::with_backend("bob".to_string(), TABLE_SIZE as i32)?;
+        alice.add_key("bob".to_string(), key.clone())?;
+        bob.add_key("alice".to_string(), key)?;
+
+        let mut servers = (0..3)
+            .map(|_| Server::<XorBackend<3>>::with_backend(TABLE_SIZE, ITEM_SIZE))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let message = vec![7u8; ITEM_SIZE];
+        let encrypted = alice.encrypt("bob".to_string(), message.clone(), 0)?;
+        let (item, _) = alice.generate_requests("bob".to_string(), encrypted, 0)?;
+        for server in &mut servers {
+            server.write(item.clone())?;
+        }
+
+        let (_, request) = bob.generate_requests("alice".to_string(), Vec::new(), 0)?;
+        let shares = servers
+            .iter()
+            .zip(&request.shares)
+            .map(|(server, share)| server.get(share))
+            .collect::<Result<_, _>>()?;
+        let rows = bob.process_responses(Response { shares })?;
+        assert_eq!(bob.decrypt("alice".to_string(), 0, rows)?, message);
+        Ok(())
+    }
+}
@@ -1,13 +1,14 @@
 #[cfg(test)]
 mod test {
     use rand::{Rng, thread_rng, RngCore};
+    use std::rc::Rc;
     use talek::{
-        client::{Client, Request, Response},
+        backend::{DpfClient, ServerMode},
+        client::{Client, Response},
         server::{Server, PirServer},
-        utils::Key,
-        PirError,
+        utils::{self, Key},
+        CryptoError, PirError,
     };
-    use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
     use cuckoo::prf;
 
     const TEST_ITEM_SIZE: usize = 64;
@@ -23,8 +24,6 @@ mod test {
 
     #[test]
     fn test_server_write_and_read() -> Result<(), PirError> {
-        let key = Key::new_random();
-
         let mut client1 = Client::new("client1".to_string(), TABLE_SIZE as i32)?;
         let mut client2 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
 
@@ -32,37 +31,46 @@ mod test {
         let mut server1 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
         let mut server2 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
 
-        client1.add_key("client2".to_string(), key.clone())?;
-        client2.add_key("client1".to_string(), key.clone())?;
+        // Agree on the topic key from the clients' identities.
+        let hello1 = client1.start_handshake("client2".to_string(), client2.public_identity());
+        let hello2 = client2.start_handshake("client1".to_string(), client1.public_identity());
+        client1.finish_handshake("client2".to_string(), &hello2)?;
+        client2.finish_handshake("client1".to_string(), &hello1)?;
+        assert_eq!(
+            client1.safety_number(&client2.public_identity()),
+            client2.safety_number(&client1.public_identity())
+        );
 
         let new_element = generate_random_data();
 
         // Convert to string and write to servers
-        let encrypted_element = client1.encrypt("client2".to_string(), new_element.clone())?;
-        let (item, Request { request1, request2 }) = client1.generate_requests("client2".to_string(), encrypted_element.clone(), 0)?;
-        let (item, Request { request1, request2 }) = client2.generate_requests("client1".to_string(), encrypted_element, 0)?;
+        let encrypted_element = client1.encrypt("client2".to_string(), new_element.clone(), 0)?;
+        let (item, request) = client1.generate_requests("client2".to_string(), encrypted_element.clone(), 0)?;
+        let (item, request) = client2.generate_requests("client1".to_string(), encrypted_element, 0)?;
 
         server1.write(item.clone())?;
         server2.write(item.clone())?;
 
-        let response1 = server1.get(&request1)?;
-        let response2 = server2.get(&request2)?;
+        let response1 = server1.get(&request.shares[0])?;
+        let response2 = server2.get(&request.shares[1])?;
         
         // Process responses
         let client1_response = client1.process_responses(Response {
-            response1: response1.clone(),
-            response2: response2.clone(),
+            shares: vec![response1.clone(), response2.clone()],
         })?;
 
         let client2_response = client2.process_responses(Response {
-            response1,
-            response2,
+            shares: vec![response1, response2],
         })?;
 
-        let decrypted_element1 = client1.decrypt("client2".to_string(), client1_response)?;
-        let decrypted_element2 = client2.decrypt("client1".to_string(), client2_response)?;
-        
-        assert_eq!(decrypted_element1, new_element);
+        // The sender cannot read its own message back as the peer's: each direction has its
+        // own key chain.
+        assert!(matches!(
+            client1.decrypt("client2".to_string(), 0, client1_response),
+            Err(PirError::Crypto(CryptoError::DecryptionFailed))
+        ));
+        let decrypted_element2 = client2.decrypt("client1".to_string(), 0, client2_response)?;
+
         assert_eq!(decrypted_element2, new_element);
 
         Ok(())
@@ -86,31 +94,225 @@ mod test {
 
         for i in 0..NUM_ELEMENTS {
             let new_element = generate_random_data();
-            let encrypted_element = client1.encrypt("client2".to_string(), new_element.clone())?;
-            let (item, Request { request1, request2 }) = client1.generate_requests("client2".to_string(), encrypted_element.clone(), i as u64)?;
+            let encrypted_element = client1.encrypt("client2".to_string(), new_element.clone(), i as u64)?;
+            let (item, request) = client1.generate_requests("client2".to_string(), encrypted_element.clone(), i as u64)?;
             
             server1.write(item.clone())?;
             server2.write(item.clone())?;
 
-            let response1 = server1.get(&request1)?;
-            let response2 = server2.get(&request2)?;
+            let response1 = server1.get(&request.shares[0])?;
+            let response2 = server2.get(&request.shares[1])?;
 
-            let client1_response = client1.process_responses(Response {
-                response1: response1.clone(),
-                response2: response2.clone(),
+            let client2_response = client2.process_responses(Response {
+                shares: vec![response1, response2],
             })?;
 
-            let client2_response = client2.process_responses(Response {
-                response1,
-                response2,
+            let decrypted_element2 = client2.decrypt("client1".to_string(), i as u64, client2_response)?;
+
+            assert_eq!(decrypted_element2, new_element);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_keeps_messages() -> Result<(), PirError> {
+        let key = Key::new_random();
+
+        let mut client1 = Client::new("client1".to_string(), TABLE_SIZE as i32)?;
+        let mut client2 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
+        client1.add_key("client2".to_string(), key.clone())?;
+        client2.add_key("client1".to_string(), key.clone())?;
+
+        let mut server1 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+        let mut server2 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+
+        let old_element = generate_random_data();
+        let encrypted = client1.encrypt("client2".to_string(), old_element.clone(), 0)?;
+        let (item, _) = client1.generate_requests("client2".to_string(), encrypted, 0)?;
+        server1.write(item.clone())?;
+        server2.write(item)?;
+
+        // Grow both servers; sequence numbers from 1 on use the new modulus.
+        server1.resize(2 * TABLE_SIZE, 1)?;
+        server2.resize(2 * TABLE_SIZE, 1)?;
+        assert_eq!(server1.size_epochs(), server2.size_epochs());
+        client1.set_size_epochs(server1.size_epochs().to_vec())?;
+        client2.set_size_epochs(server1.size_epochs().to_vec())?;
+
+        let new_element = generate_random_data();
+        let encrypted = client1.encrypt("client2".to_string(), new_element.clone(), 1)?;
+        let (item, _) = client1.generate_requests("client2".to_string(), encrypted, 1)?;
+        server1.write(item.clone())?;
+        server2.write(item)?;
+
+        for (seq_no, expected) in [(0, old_element), (1, new_element)] {
+            let (_, request) =
+                client2.generate_requests("client1".to_string(), vec![], seq_no)?;
+            let response = client2.process_responses(Response {
+                shares: vec![server1.get(&request.shares[0])?, server2.get(&request.shares[1])?],
+            })?;
+            assert_eq!(client2.decrypt("client1".to_string(), seq_no, response)?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_replaces_message() -> Result<(), PirError> {
+        let key = Key::new_random();
+
+        let mut client1 = Client::new("client1".to_string(), TABLE_SIZE as i32)?;
+        let mut client2 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
+        client1.add_key("client2".to_string(), key.clone())?;
+        client2.add_key("client1".to_string(), key.clone())?;
+
+        let mut server1 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+        let mut server2 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+
+        let encrypted = client1.encrypt("client2".to_string(), generate_random_data(), 0)?;
+        let (mut item, request) =
+            client1.generate_requests("client2".to_string(), encrypted, 0)?;
+        server1.write(item.clone())?;
+        server2.write(item.clone())?;
+
+        // Resend corrected content under the same item id; the latest sequence number can be
+        // encrypted again, but only explicitly.
+        let corrected = generate_random_data();
+        assert!(client1.encrypt("client2".to_string(), corrected.clone(), 0).is_err());
+        let (seq_no, encrypted) = client1.reencrypt("client2".to_string(), corrected.clone())?;
+        assert_eq!(seq_no, 0);
+        item.data = encrypted;
+        server1.overwrite(item.clone())?;
+        server2.overwrite(item)?;
+        assert_eq!(1, server1.num_items());
+
+        let response = client2.process_responses(Response {
+            shares: vec![server1.get(&request.shares[0])?, server2.get(&request.shares[1])?],
+        })?;
+        assert_eq!(client2.decrypt("client1".to_string(), 0, response)?, corrected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_announces_message() -> Result<(), PirError> {
+        let key = Key::new_random();
+
+        let mut client1 = Client::new("client1".to_string(), TABLE_SIZE as i32)?;
+        let mut client2 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
+        client1.add_key("client2".to_string(), key.clone())?;
+        client2.add_key("client1".to_string(), key.clone())?;
+
+        let mut server1 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+        let mut server2 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+
+        let (fingerprint, buckets) = client1.fingerprint("client2".to_string(), 3)?;
+        server1.write_fingerprint(&fingerprint, buckets.clone())?;
+        server2.write_fingerprint(&fingerprint, buckets)?;
+
+        for (seq_no, expected) in [(3, true), (4, false)] {
+            let request =
+                client2.generate_filter_requests("client1".to_string(), seq_no)?;
+            let rows = client2.process_responses(Response {
+                shares: vec![server1.get_filter(&request.shares[0])?, server2.get_filter(&request.shares[1])?],
             })?;
+            assert_eq!(expected, client2.check_filter("client1".to_string(), seq_no, rows)?);
+        }
 
-            let decrypted_element1 = client1.decrypt("client2".to_string(), client1_response)?;
-            let decrypted_element2 = client2.decrypt("client1".to_string(), client2_response)?;
+        Ok(())
+    }
 
-            assert_eq!(decrypted_element1, decrypted_element2);
+    #[test]
+    fn test_advance_epoch_expires_messages() -> Result<(), PirError> {
+        let key = Key::new_random();
+
+        let mut client1 = Client::new("client1".to_string(), TABLE_SIZE as i32)?;
+        let mut client2 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
+        client1.add_key("client2".to_string(), key.clone())?;
+        client2.add_key("client1".to_string(), key.clone())?;
+
+        let mut server1 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+        let mut server2 = Server::new(TABLE_SIZE, ITEM_SIZE)?;
+
+        // seq 0 is written in epoch 0, seq 1 in epoch 1.
+        let mut requests = Vec::new();
+        let mut messages = Vec::new();
+        for seq_no in 0..2 {
+            let message = generate_random_data();
+            let encrypted = client1.encrypt("client2".to_string(), message.clone(), seq_no)?;
+            let (item, request) = client1.generate_requests("client2".to_string(), encrypted, seq_no)?;
+            server1.write(item.clone())?;
+            server2.write(item)?;
+            requests.push(request);
+            messages.push(message);
+            if seq_no == 0 {
+                assert_eq!(0, server1.advance_epoch(1)?);
+                assert_eq!(0, server2.advance_epoch(1)?);
+            }
         }
 
+        // Keeping one epoch at epoch 2 drops what was written in epoch 0 only.
+        assert_eq!(1, server1.advance_epoch(1)?);
+        assert_eq!(1, server2.advance_epoch(1)?);
+        assert_eq!(1, server1.num_items());
+
+        let read = |client: &mut Client, server1: &Server, server2: &Server, seq_no: u64| -> Result<Vec<u8>, PirError> {
+            let request = &requests[seq_no as usize];
+            let response = client.process_responses(Response {
+                shares: vec![server1.get(&request.shares[0])?, server2.get(&request.shares[1])?],
+            })?;
+            client.decrypt("client1".to_string(), seq_no, response)
+        };
+        // Same-topic messages share buckets, so only check that the expired one is gone.
+        assert_ne!(Some(&messages[0]), read(&mut client2, &server1, &server2, 0).ok().as_ref());
+        assert_eq!(messages[1], read(&mut client2, &server1, &server2, 1)?);
+
+        // Reading a message erases its key, so check the expiry with a fresh copy of the reader.
+        let mut client3 = Client::new("client2".to_string(), TABLE_SIZE as i32)?;
+        client3.add_key("client1".to_string(), key)?;
+
+        assert_eq!(1, server1.advance_epoch(1)?);
+        assert_eq!(1, server2.advance_epoch(1)?);
+        assert_eq!(0, server1.num_items());
+        assert!(read(&mut client3, &server1, &server2, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_leader_helper_single_request() -> Result<(), PirError> {
+        let rows: Vec<Vec<u8>> = (0..TABLE_SIZE).map(|_| generate_random_data()).collect();
+        let key = Key::new_random();
+
+        let helper_key = key.clone();
+        let helper = Rc::new(PirServer::with_mode(
+            rows.clone(),
+            ServerMode::Helper(Box::new(move |ciphertext, _| {
+                utils::decrypt(&helper_key, ciphertext, b"").map_err(PirError::from)
+            })),
+        )?);
+        let forward_to = Rc::clone(&helper);
+        let leader = PirServer::with_mode(
+            rows.clone(),
+            ServerMode::Leader(Box::new(move |request| forward_to.process_request(request))),
+        )?;
+
+        let client = DpfClient::leader(
+            TABLE_SIZE,
+            Box::new(move |plaintext, _| utils::encrypt(&key, plaintext, 0, b"").map_err(PirError::from)),
+        )?;
+        let request = client.generate_leader_request(&[2, 0])?;
+        let response = leader.process_request(&request.request)?;
+        let records = client.process_leader_response(&request, &response)?;
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][..ITEM_SIZE], rows[2].as_slice());
+        assert_eq!(&records[1][..ITEM_SIZE], rows[0].as_slice());
+
+        // A plain client cannot build leader requests.
+        let plain = DpfClient::new(TABLE_SIZE)?;
+        assert!(plain.generate_leader_request(&[0]).is_err());
         Ok(())
     }
-}
\ No newline at end of file
+}